@@ -0,0 +1,122 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Write};
+use std::process::{Command, Stdio};
+
+const HISTORY_LEN: usize = 10;
+
+/// Text that was copied or cut, kept inside the editor: every yank goes into a short
+/// history, newest first, and may also be stored in a named register `a`-`z`.
+#[derive(Default)]
+pub struct Clipboard {
+    registers: HashMap<char, String>,
+    history: VecDeque<String>,
+}
+
+impl Clipboard {
+    pub fn yank(&mut self, register: Option<char>, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if let Some(register) = register {
+            self.registers.insert(register, text.to_string());
+        }
+        self.history.retain(|yanked| yanked != text);
+        self.history.push_front(text.to_string());
+        self.history.truncate(HISTORY_LEN);
+    }
+    /// The most recent yank.
+    pub fn latest(&self) -> Option<&str> {
+        self.history.front().map(String::as_str)
+    }
+    /// The contents of register `a`-`z`, or of the `n`th most recent yank for `0`-`9`.
+    pub fn get(&self, register: char) -> Option<&str> {
+        match register.to_digit(10) {
+            Some(index) => self.history.get(index as usize).map(String::as_str),
+            None => self.registers.get(&register).map(String::as_str),
+        }
+    }
+}
+
+/// Hands `text` to a clipboard helper such as `xclip`, `wl-copy` or `pbcopy` by
+/// running `command` through the shell with the text on its stdin.
+pub fn copy_with(command: &str, text: &str) -> Result<(), Error> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let written = child
+        .stdin
+        .take()
+        .map_or(Ok(()), |mut stdin| stdin.write_all(text.as_bytes()));
+    // Wait even when the helper stopped reading early, so it isn't left a zombie.
+    check(command, child.wait()?)?;
+    written
+}
+
+/// Runs a clipboard helper such as `xclip -o`, `wl-paste` or `pbpaste` through the
+/// shell and returns what it printed, with line endings turned into `\n`.
+pub fn paste_with(command: &str) -> Result<String, Error> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    check(command, output.status)?;
+    let text = String::from_utf8(output.stdout).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+    Ok(text.replace("\r\n", "\n"))
+}
+
+fn check(command: &str, status: std::process::ExitStatus) -> Result<(), Error> {
+    if status.success() {
+        return Ok(());
+    }
+    Err(Error::other(format!("`{}` failed with {}", command, status)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hecto-clipboard-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn copy_with_and_paste_with_round_trip() {
+        let path = temp_file("round-trip");
+        copy_with(&format!("cat > '{}'", path.display()), "one\r\ntwo\n").unwrap();
+        assert_eq!(paste_with(&format!("cat '{}'", path.display())).unwrap(), "one\ntwo\n");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn failing_helpers_are_errors() {
+        assert!(copy_with("cat > /dev/null; exit 3", "text").is_err());
+        assert!(paste_with("exit 1").is_err());
+        assert!(paste_with("printf '\\377'").is_err());
+    }
+
+    #[test]
+    fn helper_that_stops_reading_is_reported() {
+        let text = "x".repeat(1 << 20);
+        let error = copy_with("exit 0", &text).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn yanks_go_to_history_and_registers() {
+        let mut clipboard = Clipboard::default();
+        clipboard.yank(None, "first");
+        clipboard.yank(Some('a'), "second");
+        clipboard.yank(None, "first");
+        assert_eq!(clipboard.latest(), Some("first"));
+        assert_eq!(clipboard.get('0'), Some("first"));
+        assert_eq!(clipboard.get('1'), Some("second"));
+        assert_eq!(clipboard.get('2'), None);
+        assert_eq!(clipboard.get('a'), Some("second"));
+        assert_eq!(clipboard.get('b'), None);
+    }
+}
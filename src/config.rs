@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const AUTOSAVE_SECS: u64 = 4;
+const TAB_WIDTH: u64 = 8;
+
+/// What the gutter left of the text shows.
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum LineNumbers {
+    #[default]
+    Off,
+    Absolute,
+    /// The distance to the cursor line; the cursor line itself shows its number.
+    Relative,
+}
+
+impl LineNumbers {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Absolute,
+            Self::Absolute => Self::Relative,
+            Self::Relative => Self::Off,
+        }
+    }
+}
+
+/// How one language indents, instead of what the editor knows about it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Indent {
+    Tabs,
+    Spaces(usize),
+    /// No auto-indent for the language.
+    Off,
+}
+
+/// Editor settings, read from `HECTO_*` environment variables.
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+    /// Keep the previous contents in `file~` when saving (`HECTO_BACKUP=1`).
+    pub backup: bool,
+    /// Directory for swap files instead of next to the edited file (`HECTO_SWAP_DIR`).
+    pub swap_dir: Option<PathBuf>,
+    /// How long unsaved changes wait before going to the swap file
+    /// (`HECTO_AUTOSAVE_SECS`, 0 disables autosave).
+    pub autosave: Option<Duration>,
+    /// Line numbers in the gutter (`HECTO_LINE_NUMBERS=absolute|relative`).
+    pub line_numbers: LineNumbers,
+    /// Send copied text to the terminal's clipboard with OSC 52 (on unless `HECTO_OSC52=0`).
+    pub osc52: bool,
+    /// Shell command that receives copied text on stdin, like `xclip -selection clipboard`
+    /// (`HECTO_COPY_COMMAND`).
+    pub copy_command: Option<String>,
+    /// Shell command that prints the text to paste, like `xclip -selection clipboard -o`
+    /// (`HECTO_PASTE_COMMAND`).
+    pub paste_command: Option<String>,
+    /// Columns between tab stops (`HECTO_TAB_WIDTH`, 8 by default).
+    pub tab_width: usize,
+    /// Insert spaces up to the next tab stop when Tab is pressed (`HECTO_EXPAND_TABS=1`).
+    pub expand_tabs: bool,
+    /// Indent new lines to match the code around them (on unless `HECTO_AUTO_INDENT=0`).
+    pub auto_indent: bool,
+    /// Indentation per language, by lower-case language name
+    /// (`HECTO_INDENT_<LANGUAGE>=tab|<spaces>|off`, like `HECTO_INDENT_C=tab`).
+    pub indents: HashMap<String, Indent>,
+    /// Start with long lines wrapped at word boundaries (`HECTO_SOFT_WRAP=1`).
+    pub soft_wrap: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            backup: flag("HECTO_BACKUP"),
+            swap_dir: env::var_os("HECTO_SWAP_DIR").map(PathBuf::from),
+            autosave: match number("HECTO_AUTOSAVE_SECS").unwrap_or(AUTOSAVE_SECS) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            line_numbers: match env::var("HECTO_LINE_NUMBERS").unwrap_or_default().to_lowercase().as_str() {
+                "1" | "on" | "absolute" => LineNumbers::Absolute,
+                "relative" => LineNumbers::Relative,
+                _ => LineNumbers::Off,
+            },
+            osc52: env::var_os("HECTO_OSC52").is_none() || flag("HECTO_OSC52"),
+            copy_command: command("HECTO_COPY_COMMAND"),
+            paste_command: command("HECTO_PASTE_COMMAND"),
+            tab_width: usize::try_from(number("HECTO_TAB_WIDTH").unwrap_or(TAB_WIDTH))
+                .unwrap_or(1)
+                .clamp(1, 32),
+            expand_tabs: flag("HECTO_EXPAND_TABS"),
+            auto_indent: env::var_os("HECTO_AUTO_INDENT").is_none() || flag("HECTO_AUTO_INDENT"),
+            indents: env::vars()
+                .filter_map(|(name, value)| {
+                    let language = name.strip_prefix("HECTO_INDENT_")?.to_lowercase();
+                    Some((language, indent(&value)?))
+                })
+                .collect(),
+            soft_wrap: flag("HECTO_SOFT_WRAP"),
+        }
+    }
+
+    /// The indentation configured for `language`, as `FileType::name` calls it.
+    pub fn indent(&self, language: &str) -> Option<Indent> {
+        self.indents.get(&language.to_lowercase()).copied()
+    }
+}
+
+fn indent(value: &str) -> Option<Indent> {
+    match value.trim().to_lowercase().as_str() {
+        "tab" | "tabs" => Some(Indent::Tabs),
+        "off" => Some(Indent::Off),
+        spaces => spaces.parse().ok().filter(|spaces| (1..=16).contains(spaces)).map(Indent::Spaces),
+    }
+}
+
+fn flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| {
+        matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+    })
+}
+
+fn number(name: &str) -> Option<u64> {
+    env::var(name).ok()?.trim().parse().ok()
+}
+
+fn command(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|command| !command.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indent_settings_parse() {
+        assert!(indent("tab") == Some(Indent::Tabs));
+        assert!(indent(" 2 ") == Some(Indent::Spaces(2)));
+        assert!(indent("OFF") == Some(Indent::Off));
+        assert!(indent("0").is_none());
+        assert!(indent("wide").is_none());
+    }
+}
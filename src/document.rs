@@ -1,40 +1,344 @@
 use crate::Row;
+use crate::FileType;
+use crate::IndentOptions;
+use crate::highlighting;
 use crate::Position;
 use crate::SearchDirection;
+use crate::history::{History, Operation};
+use crate::search::Query;
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
-use std::io::{Error, Write};
-#[derive(Default)]
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::io::{Error, ErrorKind, Write};
+const BOM: char = '\u{feff}';
+// How many rows from the top decide whether the file indents with tabs or spaces.
+const INDENT_SAMPLE: usize = 1000;
+
+#[derive(PartialEq, Clone, Copy, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    /// Some lines end in `\r\n` and others in `\n`. Rows keep their `\r` and are joined
+    /// with `\n`, so the file is written back byte for byte until it is converted.
+    Mixed,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf | Self::Mixed => "\n",
+            Self::CrLf => "\r\n",
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::CrLf => "CRLF",
+            Self::Mixed => "Mixed",
+        }
+    }
+    fn detect(contents: &str) -> Self {
+        let mut lines = contents.split('\n');
+        lines.next_back();
+        let (crlf, lf) = lines.fold((0_usize, 0_usize), |(crlf, lf), line| {
+            if line.ends_with('\r') {
+                (crlf.saturating_add(1), lf)
+            } else {
+                (crlf, lf.saturating_add(1))
+            }
+        });
+        match (crlf, lf) {
+            (0, _) => Self::Lf,
+            (_, 0) => Self::CrLf,
+            _ => Self::Mixed,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum DiskStatus {
+    Unchanged,
+    Modified,
+    Deleted,
+}
+
+// What the file looked like when it was last read or written.
+#[derive(Clone, Copy)]
+struct DiskStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl DiskStamp {
+    fn new(metadata: &fs::Metadata, contents: &[u8]) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: hash(contents),
+        }
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
 pub struct Document {
     rows: Vec<Row>,
     pub file_name: Option<String>,
     dirty: bool,
+    history: History,
+    line_ending: LineEnding,
+    bom: bool,
+    final_newline: bool,
+    // The document had no rows, not even an empty one, before its first edit.
+    started_empty: bool,
+    revision: u64,
+    disk_stamp: Option<DiskStamp>,
+    file_type: FileType,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self {
+            rows: Vec::new(),
+            file_name: None,
+            dirty: false,
+            history: History::default(),
+            line_ending: LineEnding::default(),
+            bom: false,
+            final_newline: true,
+            started_empty: true,
+            revision: 0,
+            disk_stamp: None,
+            file_type: FileType::default(),
+        }
+    }
+}
+
+impl From<&str> for Document {
+    fn from(contents: &str) -> Self {
+        Self::from_contents(contents)
+    }
 }
 
 impl Document {
     pub fn open(filename: &str) -> Result<Self, std::io::Error> {
         let contents = fs::read_to_string(filename)?;
+        let metadata = fs::metadata(filename)?;
+        Ok(Self {
+            file_name: Some(filename.to_string()),
+            disk_stamp: Some(DiskStamp::new(&metadata, contents.as_bytes())),
+            file_type: FileType::from(filename),
+            ..Self::from_contents(&contents)
+        })
+    }
+
+    /// Opens the autosaved contents in `swap_path` as an unsaved buffer for `filename`.
+    pub fn recover(filename: &str, swap_path: &Path) -> Result<Self, std::io::Error> {
+        let contents = fs::read_to_string(swap_path)?;
+        let disk_stamp = fs::read(filename)
+            .and_then(|on_disk| Ok(DiskStamp::new(&fs::metadata(filename)?, &on_disk)))
+            .ok();
+        let mut document = Self {
+            file_name: Some(filename.to_string()),
+            dirty: true,
+            disk_stamp,
+            file_type: FileType::from(filename),
+            ..Self::from_contents(&contents)
+        };
+        document.history.forget_saved();
+        Ok(document)
+    }
+
+    fn from_contents(contents: &str) -> Self {
+        let bom = contents.starts_with(BOM);
+        let contents = contents.strip_prefix(BOM).unwrap_or(contents);
+        let line_ending = LineEnding::detect(contents);
+        let final_newline = contents.ends_with('\n');
+        let contents = contents.strip_suffix('\n').unwrap_or(contents);
         let mut rows = Vec::new();
-        for line in contents.lines() {
-            rows.push(Row::from(line));
+        if !contents.is_empty() || final_newline {
+            let mut lines = contents.split('\n').peekable();
+            while let Some(line) = lines.next() {
+                // A `\r` is only part of the line ending in front of a line break.
+                let ends_line = lines.peek().is_some() || final_newline;
+                let line = match line_ending {
+                    LineEnding::CrLf if ends_line => line.strip_suffix('\r').unwrap_or(line),
+                    _ => line,
+                };
+                rows.push(Row::from(line));
+            }
         }
-        Ok(Self { 
+        Self { 
+            started_empty: rows.is_empty(),
             rows,
-            file_name: Some(filename.to_string()),
-            dirty: false,
-         })
+            final_newline: final_newline || contents.is_empty(),
+            line_ending,
+            bom,
+            ..Self::default()
+        }
     }
 
-    pub fn save(&mut self) -> Result<(), Error> {
+    /// Writes the document to a temporary file next to the target, syncs it and renames
+    /// it over the original, so a crash never leaves a half-written file behind.
+    pub fn save(&mut self, backup: bool) -> Result<(), Error> {
         if let Some(file_name) = &self.file_name {
-            let mut file = fs::File::create(file_name)?;
-            for row in &self.rows {
-                file.write_all(row.as_bytes())?;
-                file.write_all(b"\n")?;
-            }  
+            let file_type = FileType::from(file_name);
+            if file_type.name() != self.file_type.name() {
+                self.file_type = file_type;
+                for row in &mut self.rows {
+                    row.unhighlight();
+                }
+            }
+            // Write through symlinks instead of replacing them with a regular file.
+            let path = fs::canonicalize(file_name).unwrap_or_else(|_| PathBuf::from(file_name));
+            let metadata = fs::metadata(&path).ok();
+            if backup && metadata.is_some() {
+                let mut backup_path = path.clone().into_os_string();
+                backup_path.push("~");
+                fs::copy(&path, &backup_path)
+                    .map_err(|error| context(&error, "could not write backup", Path::new(&backup_path)))?;
+            }
+            let temp_path = temp_path(&path);
+            let contents = self.contents();
+            match write_temp(&temp_path, &contents, metadata.as_ref()) {
+                Ok(()) => {
+                    if let Err(error) = fs::rename(&temp_path, &path) {
+                        let _ = fs::remove_file(&temp_path);
+                        return Err(context(&error, "could not replace", &path));
+                    }
+                    sync_dir(&path);
+                }
+                // A file we may write but not replace, such as a group-writable file owned
+                // by someone else or one in a read-only directory, is overwritten in place.
+                Err(error) if error.kind() == ErrorKind::PermissionDenied && metadata.is_some() => {
+                    let _ = fs::remove_file(&temp_path);
+                    write_in_place(&path, &contents)?;
+                }
+                Err(error) => {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(error);
+                }
+            }
+            self.disk_stamp = fs::metadata(&path)
+                .ok()
+                .map(|metadata| DiskStamp::new(&metadata, &contents));
             self.dirty = false;
+            self.history.mark_saved();
         }
         Ok(())
     }
+    /// The bytes `save` writes: rows joined with the document's line ending, plus the
+    /// BOM and final newline if the file had them.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::new();
+        if self.bom {
+            contents.extend_from_slice(BOM.to_string().as_bytes());
+        }
+        let line_ending = self.line_ending.as_str().as_bytes();
+        for (index, row) in self.rows.iter().enumerate() {
+            if index > 0 {
+                contents.extend_from_slice(line_ending);
+            }
+            for chunk in row.chunks() {
+                contents.extend_from_slice(chunk.as_bytes());
+            }
+        }
+        if self.final_newline && !self.rows.is_empty() {
+            contents.extend_from_slice(line_ending);
+        }
+        contents
+    }
+    /// Compares the file on disk with what was last read or written. A changed
+    /// timestamp alone (e.g. `touch`) does not count as a modification.
+    pub fn disk_status(&mut self) -> DiskStatus {
+        let (Some(file_name), Some(stamp)) = (&self.file_name, self.disk_stamp) else {
+            return DiskStatus::Unchanged;
+        };
+        let metadata = match fs::metadata(file_name) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return DiskStatus::Deleted,
+            Err(_) => return DiskStatus::Unchanged,
+        };
+        if metadata.modified().ok() == stamp.modified && metadata.len() == stamp.len {
+            return DiskStatus::Unchanged;
+        }
+        match fs::read(file_name) {
+            Ok(on_disk) if hash(&on_disk) == stamp.hash => {
+                self.disk_stamp = Some(DiskStamp::new(&metadata, &on_disk));
+                DiskStatus::Unchanged
+            }
+            Ok(_) => DiskStatus::Modified,
+            Err(_) => DiskStatus::Unchanged,
+        }
+    }
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+    pub fn has_bom(&self) -> bool {
+        self.bom
+    }
+    /// Converting a file with mixed line endings removes the `\r` its rows kept, as one
+    /// undo step.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.line_ending == LineEnding::Mixed {
+            // Without a final newline, a `\r` at the end of the last row is text, not
+            // part of a line ending.
+            let ended = if self.final_newline { self.rows.len() } else { self.rows.len().saturating_sub(1) };
+            self.history.begin_group();
+            for y in 0..ended {
+                let Some(row) = self.rows.get(y) else {
+                    break;
+                };
+                let len = row.len();
+                if row.grapheme(len.saturating_sub(1)) == Some("\r") {
+                    self.delete_range(&Position { x: len.saturating_sub(1), y }, &Position { x: len, y });
+                }
+            }
+            self.history.end_group();
+        }
+        if self.line_ending != line_ending {
+            self.line_ending = line_ending;
+            self.dirty = true;
+            self.revision = self.revision.wrapping_add(1);
+            self.history.forget_saved();
+        }
+    }
+    pub fn file_type(&self) -> String {
+        self.file_type.name()
+    }
+    pub fn indent_options(&self) -> &IndentOptions {
+        self.file_type.indent_options()
+    }
+    /// Whether more of the rows near the top start with a tab (`Some(true)`) than with
+    /// spaces (`Some(false)`); `None` for a tie. A single leading space, like the one
+    /// before the `*` inside a block comment, doesn't count.
+    pub fn indents_with_tabs(&self) -> Option<bool> {
+        let (tabs, spaces) = self.rows.iter().take(INDENT_SAMPLE).fold((0_usize, 0_usize), |(tabs, spaces), row| {
+            match (row.grapheme(0), row.grapheme(1)) {
+                (Some("\t"), _) => (tabs.saturating_add(1), spaces),
+                (Some(" "), Some(" ")) => (tabs, spaces.saturating_add(1)),
+                _ => (tabs, spaces),
+            }
+        });
+        match tabs.cmp(&spaces) {
+            std::cmp::Ordering::Greater => Some(true),
+            std::cmp::Ordering::Less => Some(false),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+    /// Highlights the rows up to `until`. Multi-line constructs carry their state from
+    /// row to row, and a row is only lexed again when it was edited or the state it
+    /// starts in changed, so an edit re-lexes just the rows it actually affects.
+    pub fn highlight(&mut self, until: usize) {
+        let until = until.min(self.rows.len());
+        let mut state = highlighting::State::Normal;
+        for row in &mut self.rows[..until] {
+            state = row.highlight(self.file_type.highlighting_options(), state);
+        }
+    }
     pub fn row(&self, index:usize) -> Option<&Row> {
         self.rows.get(index)
     }
@@ -49,56 +353,273 @@ impl Document {
             return;
         }
         self.dirty = true;
-        if c == '\n' {
-            self.insert_newline(at);
-            return;
-        }
+        self.revision = self.revision.wrapping_add(1);
         if at.y == self.rows.len() {
-            let mut row = Row::default();
-            row.insert(0,c);
-            self.rows.push(row);
+            self.append_row();
+            if c == '\n' {
+                return;
+            }
+        }
+        let at = self.byte_position(at);
+        let text = c.to_string();
+        self.insert_text(&at, &text);
+        self.history.record(Operation::Insert { at, text });
+    }
+    /// Inserts `text`, which may span several lines, as a single edit and returns the
+    /// position right after it.
+    pub fn insert_str(&mut self, at: &Position, text: &str) -> Position {
+        if at.y > self.rows.len() || text.is_empty() {
+            return at.clone();
+        }
+        self.dirty = true;
+        self.revision = self.revision.wrapping_add(1);
+        let (at, text) = match self.rows.last() {
+            Some(last) if at.y == self.rows.len() => (
+                Position {
+                    x: last.byte_index(last.len()),
+                    y: at.y.saturating_sub(1),
+                },
+                format!("\n{}", text),
+            ),
+            _ => (self.byte_position(at), text.to_string()),
+        };
+        let end = self.insert_text(&at, &text);
+        self.history.record(Operation::Insert { at, text });
+        self.grapheme_position(&end)
+    }
+    /// Removes everything from `start` up to `end` as a single edit and returns it.
+    pub fn delete_range(&mut self, start: &Position, end: &Position) -> String {
+        let text = self.text_range(start, end);
+        if text.is_empty() {
+            return text;
+        }
+        self.dirty = true;
+        self.revision = self.revision.wrapping_add(1);
+        let start = self.byte_position(start);
+        self.delete_text(&start, &text);
+        self.history.record(Operation::Delete {
+            at: start,
+            text: text.clone(),
+        });
+        text
+    }
+    /// Replaces everything from `start` up to `end` with `text` as one undo step and
+    /// returns the position just after the inserted text.
+    pub fn replace_range(&mut self, start: &Position, end: &Position, text: &str) -> Position {
+        self.history.begin_group();
+        self.delete_range(start, end);
+        let end = self.insert_str(start, text);
+        self.history.end_group();
+        end
+    }
+    /// The text from `start` up to `end`, with rows joined by `\n`.
+    pub fn text_range(&self, start: &Position, end: &Position) -> String {
+        let mut text = String::new();
+        for y in start.y..=end.y {
+            let Some(row) = self.rows.get(y) else {
+                break;
+            };
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            if y > start.y {
+                text.push('\n');
+            }
+            text.push_str(&row.substring(from, to));
+        }
+        text
+    }
+    /// Everything from `begin_undo_group` to `end_undo_group` is undone in one step.
+    pub fn begin_undo_group(&mut self) {
+        self.history.begin_group();
+    }
+    pub fn end_undo_group(&mut self) {
+        self.history.end_group();
+    }
+    // Typing below the last row appends a line, which is the same as a line break at the
+    // end of the last row. In an empty document it is the first row, which `undo` takes
+    // away again once everything is undone.
+    fn append_row(&mut self) {
+        if let Some(last) = self.rows.last() {
+            let end = Position {
+                x: last.byte_index(last.len()),
+                y: self.rows.len().saturating_sub(1),
+            };
+            self.insert_text(&end, "\n");
+            self.history.record(Operation::Insert {
+                at: end,
+                text: "\n".to_string(),
+            });
         } else {
-            #[allow(clippy::indexing_slicing)]            
-            let row = &mut self.rows[at.y];
-            row.insert(at.x , c);
+            // Recorded as an empty insert, so Enter in an empty document can be undone.
+            self.rows.push(Row::default());
+            self.history.record(Operation::Insert {
+                at: Position::default(),
+                text: String::new(),
+            });
         }
     }
-    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
     pub fn delete(&mut self , at: &Position) {
+        let byte_position = self.byte_position(at);
+        if let Some(text) = self.delete_grapheme(at) {
+            self.dirty = true;
+            self.revision = self.revision.wrapping_add(1);
+            self.history.record(Operation::Delete {
+                at: byte_position,
+                text,
+            });
+        }
+    }
+    /// Reverts the most recent undo step and returns where the cursor should go.
+    pub fn undo(&mut self) -> Option<Position> {
+        let group = self.history.undo()?;
+        let mut cursor = None;
+        for operation in group.iter().rev() {
+            let at = match operation {
+                Operation::Insert { at, text } => {
+                    self.delete_text(at, text);
+                    at
+                }
+                Operation::Delete { at, text } => {
+                    self.insert_text(at, text);
+                    at
+                }
+            };
+            cursor = Some(self.grapheme_position(at));
+        }
+        // The first edit gave an empty document its first row, which no operation records.
+        if self.started_empty && !self.history.can_undo() && self.rows.iter().all(Row::is_empty) {
+            self.rows.clear();
+        }
+        self.dirty = !self.history.is_at_saved();
+        self.revision = self.revision.wrapping_add(1);
+        cursor
+    }
+    /// Re-applies the most recently undone step and returns where the cursor should go.
+    pub fn redo(&mut self) -> Option<Position> {
+        let group = self.history.redo()?;
+        let mut cursor = None;
+        for operation in &group {
+            let at = match operation {
+                Operation::Insert { at, text } => self.insert_text(at, text),
+                Operation::Delete { at, text } => {
+                    self.delete_text(at, text);
+                    at.clone()
+                }
+            };
+            cursor = Some(self.grapheme_position(&at));
+        }
+        self.dirty = !self.history.is_at_saved();
+        self.revision = self.revision.wrapping_add(1);
+        cursor
+    }
+    // History positions count bytes rather than graphemes: an edit can merge with the
+    // cluster next to it, so grapheme positions recorded before it don't replay.
+    fn byte_position(&self, at: &Position) -> Position {
+        Position {
+            x: self.rows.get(at.y).map_or(0, |row| row.byte_index(at.x)),
+            y: at.y,
+        }
+    }
+    fn grapheme_position(&self, at: &Position) -> Position {
+        Position {
+            x: self.rows.get(at.y).map_or(0, |row| row.nearest_grapheme(at.x)),
+            y: at.y,
+        }
+    }
+    // Breaks the row at byte position `at`.
+    fn split_row(&mut self, at: &Position) {
+        if at.y == self.rows.len() {
+            self.rows.push(Row::default());
+            return;
+        }
+        if let Some(row) = self.rows.get_mut(at.y) {
+            let new_row = row.split_off(at.x);
+            self.rows.insert(at.y.saturating_add(1), new_row);
+        }
+    }
+    // Removes the grapheme at `at` (or the line break when `at` is at the end of a row)
+    // and returns what was removed.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn delete_grapheme(&mut self, at: &Position) -> Option<String> {
         let len = self.rows.len();
         if at.y >= len {
-            return;
+            return None;
         }
-        self.dirty = true;
         if at.x == self.rows[at.y].len() && at.y + 1 < len {
             let next_row = self.rows.remove(at.y + 1);
             let row = &mut self.rows[at.y];
             row.append(&next_row);
+            Some("\n".to_string())
         } else {
             let row = &mut self.rows[at.y];
+            let removed = row.grapheme(at.x)?.to_string();
             row.delete(at.x);
-               
+            Some(removed)
         }
     }
-    pub fn insert_newline(&mut self, at: &Position) {
-        if at.y > self.rows.len() {
+    // Inserts `text` at byte position `at` without recording it and returns the byte
+    // position right after it.
+    fn insert_text(&mut self, at: &Position, text: &str) -> Position {
+        let mut position = at.clone();
+        for (index, line) in text.split('\n').enumerate() {
+            if index > 0 {
+                self.split_row(&position);
+                position.y = position.y.saturating_add(1);
+                position.x = 0;
+            }
+            if position.y == self.rows.len() {
+                self.rows.push(Row::default());
+            }
+            if let Some(row) = self.rows.get_mut(position.y) {
+                row.replace_bytes(position.x, position.x, line);
+                position.x = position.x.saturating_add(line.len());
+            }
+        }
+        position
+    }
+    // Removes `text`, which must be what the document holds at byte position `at`,
+    // without recording it.
+    #[allow(clippy::indexing_slicing)]
+    fn delete_text(&mut self, at: &Position, text: &str) {
+        let lines = text.split('\n').count().saturating_sub(1);
+        let last = text.rsplit('\n').next().unwrap_or_default().len();
+        let end_y = at.y.saturating_add(lines);
+        if end_y >= self.rows.len() {
             return;
         }
-        if at.y == self.rows.len() {
-            self.rows.push(Row::default());
+        if lines == 0 {
+            self.rows[at.y].replace_bytes(at.x, at.x.saturating_add(last), "");
             return;
-        } 
-        
-        #[allow(clippy::indexing_slicing)]            
-        let new_row = self.rows[at.y].split(at.x);
-        #[allow(clippy::integer_arithmetic)]
-        self.rows.insert(at.y + 1, new_row);
+        }
+        let tail = self.rows[end_y].split_off(last);
+        self.rows.drain(at.y.saturating_add(1)..=end_y);
+        let row = &mut self.rows[at.y];
+        row.split_off(at.x);
+        row.append(&tail);
     }
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
+    /// Changes whenever the contents change, including through undo and redo.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+    pub fn find(&self, query: &Query, at: &Position, direction: SearchDirection) -> Option<Position> {
+        self.find_match(query, at, direction).map(|(start, _)| start)
+    }
+    /// Start and end of every match in the document, in order.
+    pub fn find_all(&self, query: &Query) -> Vec<(Position, Position)> {
+        let mut matches = Vec::new();
+        for (y, row) in self.rows.iter().enumerate() {
+            for (start, end) in row.find_all(query) {
+                matches.push((Position { x: start, y }, Position { x: end, y }));
+            }
+        }
+        matches
+    }
+    /// Like `find`, but also returns where the match ends.
     #[allow(clippy::indexing_slicing)]
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
+    pub fn find_match(&self, query: &Query, at: &Position, direction: SearchDirection) -> Option<(Position, Position)> {
         if at.y >= self.rows.len() {
             return None;
         }
@@ -113,9 +634,10 @@ impl Document {
 
         for _ in start..end {
             if let Some(row) = self.rows.get(position.y) {
-                if let Some(x) = row.find(&query, position.x, direction) {
-                    position.x = x;
-                    return Some(position);
+                if let Some((start, end)) = row.find_match(query, position.x, direction) {
+                    let end = Position { x: end, y: position.y };
+                    position.x = start;
+                    return Some((position, end));
                 }
                 // Search nexe line or previous line
                 if direction == SearchDirection::Forward {
@@ -131,4 +653,280 @@ impl Document {
         }
         None
     }   
-}
\ No newline at end of file
+}
+
+fn hash(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn context(error: &Error, action: &str, path: &Path) -> Error {
+    Error::new(error.kind(), format!("{} {}: {}", action, path.display(), error))
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".hecto-{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+fn write_temp(temp_path: &Path, contents: &[u8], original: Option<&fs::Metadata>) -> Result<(), Error> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(temp_path)
+        .map_err(|error| context(&error, "could not create", temp_path))?;
+    file.write_all(contents)
+        .map_err(|error| context(&error, "could not write", temp_path))?;
+    if let Some(original) = original {
+        fs::set_permissions(temp_path, original.permissions())
+            .map_err(|error| context(&error, "could not set permissions on", temp_path))?;
+        keep_owner(temp_path, original)?;
+    }
+    file.sync_all()
+        .map_err(|error| context(&error, "could not sync", temp_path))
+}
+
+// Truncates and rewrites `path`, which keeps its owner and permissions. Unlike a
+// rename this isn't crash-safe, so it is only the fallback.
+fn write_in_place(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|error| context(&error, "could not open", path))?;
+    file.write_all(contents)
+        .map_err(|error| context(&error, "could not write", path))?;
+    file.sync_all()
+        .map_err(|error| context(&error, "could not sync", path))
+}
+
+#[cfg(unix)]
+fn keep_owner(temp_path: &Path, original: &fs::Metadata) -> Result<(), Error> {
+    use std::os::unix::fs::MetadataExt;
+    let temp = fs::metadata(temp_path)?;
+    if temp.uid() == original.uid() && temp.gid() == original.gid() {
+        return Ok(());
+    }
+    std::os::unix::fs::chown(temp_path, Some(original.uid()), Some(original.gid()))
+        .map_err(|error| context(&error, "could not keep the owner of the original on", temp_path))
+}
+
+#[cfg(not(unix))]
+fn keep_owner(_temp_path: &Path, _original: &fs::Metadata) -> Result<(), Error> {
+    Ok(())
+}
+
+// Makes the rename itself durable. Failing here is not worth reporting: the data is
+// already on disk under its final name.
+fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn at(x: usize, y: usize) -> Position {
+        Position { x, y }
+    }
+
+    fn text(document: &Document) -> String {
+        String::from_utf8_lossy(&document.contents()).into_owned()
+    }
+
+    #[test]
+    fn undo_of_a_combining_mark_removes_only_the_mark() {
+        let mut document = Document::from("ab\n");
+        document.insert(&at(1, 0), '\u{301}');
+        assert_eq!(text(&document), "a\u{301}b\n");
+        assert_eq!(document.row(0).map(Row::len), Some(2));
+        document.undo();
+        assert_eq!(text(&document), "ab\n");
+        document.redo();
+        assert_eq!(text(&document), "a\u{301}b\n");
+    }
+
+    #[test]
+    fn undo_of_a_deleted_combining_mark_restores_the_cluster() {
+        let mut document = Document::from("a\u{301}b\n");
+        document.delete(&at(0, 0));
+        assert_eq!(text(&document), "b\n");
+        document.undo();
+        assert_eq!(text(&document), "a\u{301}b\n");
+    }
+
+    #[test]
+    fn undo_of_a_multi_line_insert_into_a_cluster() {
+        let mut document = Document::from("ab\n");
+        let end = document.insert_str(&at(1, 0), "\u{301}x\ny");
+        assert_eq!(text(&document), "a\u{301}x\nyb\n");
+        assert!(end == at(1, 1));
+        document.undo();
+        assert_eq!(text(&document), "ab\n");
+        document.redo();
+        assert_eq!(text(&document), "a\u{301}x\nyb\n");
+    }
+
+    #[test]
+    fn typing_undoes_as_one_step_and_redoes() {
+        let mut document = Document::from("\n");
+        for (x, c) in "héllo".chars().enumerate() {
+            document.insert(&at(x, 0), c);
+        }
+        assert_eq!(text(&document), "héllo\n");
+        let cursor = document.undo();
+        assert!(cursor == Some(at(0, 0)));
+        assert_eq!(text(&document), "\n");
+        assert!(!document.is_dirty());
+        let cursor = document.redo();
+        assert!(cursor == Some(at(5, 0)));
+        assert_eq!(text(&document), "héllo\n");
+        assert!(document.is_dirty());
+    }
+
+    #[test]
+    fn newlines_and_joined_rows_round_trip() {
+        let mut document = Document::from("one\ntwo\n");
+        document.insert(&at(2, 0), '\n');
+        assert_eq!(text(&document), "on\ne\ntwo\n");
+        document.delete(&at(1, 1));
+        assert_eq!(text(&document), "on\netwo\n");
+        document.undo();
+        assert_eq!(text(&document), "on\ne\ntwo\n");
+        document.undo();
+        assert_eq!(text(&document), "one\ntwo\n");
+        document.redo();
+        document.redo();
+        assert_eq!(text(&document), "on\netwo\n");
+    }
+
+    #[test]
+    fn a_paste_after_typing_is_undone_separately() {
+        let mut document = Document::from("\n");
+        document.insert(&at(0, 0), 'a');
+        document.insert(&at(1, 0), 'b');
+        document.insert_str(&at(2, 0), "xyz");
+        document.undo();
+        assert_eq!(text(&document), "ab\n");
+        document.undo();
+        assert_eq!(text(&document), "\n");
+    }
+
+    #[test]
+    fn replace_range_is_one_step() {
+        let mut document = Document::from("hello world\n");
+        let end = document.replace_range(&at(0, 0), &at(5, 0), "bye");
+        assert!(end == at(3, 0));
+        assert_eq!(text(&document), "bye world\n");
+        document.undo();
+        assert_eq!(text(&document), "hello world\n");
+        assert!(document.undo().is_none());
+    }
+
+    #[test]
+    fn line_endings_are_detected() {
+        assert!(Document::from("a\nb\n").line_ending() == LineEnding::Lf);
+        assert!(Document::from("a\r\nb\r\n").line_ending() == LineEnding::CrLf);
+        assert!(Document::from("a\r\nb\nc\n").line_ending() == LineEnding::Mixed);
+        assert!(Document::from("x\ny\r\n").line_ending() == LineEnding::Mixed);
+        assert!(Document::from("a\r").line_ending() == LineEnding::Lf);
+    }
+
+    #[test]
+    fn files_are_written_back_unchanged() {
+        for contents in ["a\n", "a", "", "\n", "a\r\nb\r\n", "\u{feff}x\r\ny", "a\r\nb\nc\n", "x\ny\r\n", "a\r\nb\r"] {
+            assert_eq!(text(&Document::from(contents)), contents);
+        }
+    }
+
+    #[test]
+    fn mixed_line_endings_are_converted_on_request() {
+        let mut document = Document::from("a\r\nb\nc\r\n");
+        assert_eq!(document.row(0).map(Row::len), Some(2));
+        document.set_line_ending(LineEnding::Lf);
+        assert_eq!(text(&document), "a\nb\nc\n");
+        document.set_line_ending(LineEnding::CrLf);
+        assert_eq!(text(&document), "a\r\nb\r\nc\r\n");
+        assert!(document.is_dirty());
+    }
+
+    #[test]
+    fn converting_keeps_a_carriage_return_without_a_line_break() {
+        let mut document = Document::from("a\r\nb\nc\r");
+        document.set_line_ending(LineEnding::Lf);
+        assert_eq!(text(&document), "a\nb\nc\r");
+    }
+
+    #[test]
+    fn undoing_everything_in_an_empty_document_empties_it() {
+        for key in ['a', '\n'] {
+            let mut document = Document::from("");
+            document.insert(&at(0, 0), key);
+            assert!(document.is_dirty());
+            document.undo();
+            assert_eq!(text(&document), "");
+            assert!(!document.is_dirty());
+            document.redo();
+            assert_eq!(text(&document), if key == 'a' { "a\n" } else { "\n" });
+        }
+        let mut document = Document::from("\n");
+        document.insert(&at(0, 0), 'a');
+        document.undo();
+        assert_eq!(text(&document), "\n");
+    }
+
+    #[test]
+    fn typing_below_the_last_row_undoes() {
+        let mut document = Document::from("a");
+        document.insert(&at(0, 1), 'b');
+        assert_eq!(text(&document), "a\nb");
+        document.undo();
+        assert_eq!(text(&document), "a\n");
+        document.undo();
+        assert_eq!(text(&document), "a");
+    }
+
+    // The average time of a keystroke in the middle of a line of Rust `length` bytes
+    // long: the edit, highlighting the rows on screen, the cursor's column and drawing
+    // the screen columns around it, as the editor does for every key.
+    fn keystroke_time(length: usize) -> Duration {
+        const KEYS: usize = 200;
+        let piece = "let x = foo(1, \"s\"); ";
+        let line = piece.repeat(length / piece.len());
+        let mut document = Document {
+            file_type: FileType::from("line.rs"),
+            ..Document::from(line.as_str())
+        };
+        document.highlight(1);
+        let middle = line.len() / 2;
+        let started = Instant::now();
+        for (offset, c) in "fn(x) + y; ".chars().cycle().take(KEYS).enumerate() {
+            let x = middle.saturating_add(offset);
+            document.insert(&at(x, 0), c);
+            document.highlight(1);
+            let row = document.row(0).unwrap();
+            let column = row.column(x.saturating_add(1), 8);
+            assert!(!row.render(column.saturating_sub(40), column.saturating_add(40), &[], 8).is_empty());
+        }
+        started.elapsed() / u32::try_from(KEYS).unwrap()
+    }
+
+    #[test]
+    #[ignore = "benchmark: cargo test --release keystroke -- --ignored --nocapture"]
+    fn keystroke_cost_does_not_grow_with_the_line() {
+        let short = keystroke_time(1 << 10);
+        let long = keystroke_time(4 << 20);
+        eprintln!("per keystroke: {:?} on a 1 KB line, {:?} on a 4 MB line", short, long);
+        assert!(long < short * 50);
+    }
+}
@@ -1,26 +1,64 @@
 use crate::Terminal;
 use crate::Document;
 use crate::Row;
+use crate::row;
+use crate::highlighting;
+use crate::LineEnding;
+use crate::DiskStatus;
+use crate::Config;
+use crate::LineNumbers;
+use crate::Indent;
+use crate::swap;
+use crate::clipboard::{self, Clipboard};
+use crate::search::{CaseMode, Matches, Query, SearchOptions};
+use std::cmp;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 use std::io::{self};
 use std::time::{Instant, Duration};
 use std::env;
+use std::path::{Path, PathBuf};
 use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode},
-    event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
-    style::Color,
+    event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
+    style::{Color, ResetColor, SetForegroundColor},
 };
 
 const STATUS_FG_COLOR:Color = Color::DarkGreen;
 const STATUS_BG_COLOR:Color = Color::Grey;
+const GUTTER_FG_COLOR:Color = Color::DarkGrey;
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const QUIT_TIMES: u8 = 3;
+const TICK: Duration = Duration::from_secs(1);
+const WHEEL_LINES: usize = 3;
+const REGISTERS: &str = "abcdefghijklmnopqrstuvwxyz";
+const HISTORY_REGISTERS: &str = "0123456789";
+const KEYS: &str = "\
+Ctrl-S          save
+Ctrl-Q          quit
+Ctrl-F          find (Ctrl-R regex, Ctrl-E case, Ctrl-W whole word while typing)
+Ctrl-R          find and replace
+Ctrl-G          go to line[:column]
+Ctrl-Z / Ctrl-Y undo / redo
+Shift-arrows    select
+Ctrl-C          copy
+Ctrl-X          cut
+Ctrl-V          paste
+Ctrl-K / Ctrl-P copy to / paste from a register
+Ctrl-arrows     move by word or paragraph, Ctrl-Home/End to the ends of the file
+Ctrl-Backspace  delete the word before the cursor, Ctrl-Delete the one after
+Ctrl-E          convert line endings between LF and CRLF
+Ctrl-N          line numbers: off, absolute, relative
+Ctrl-W          soft wrap on/off
+F1              this list
+";
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum SearchDirection {
     Forward,
     Backward,
 }
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq)]
 pub struct  Position {
     pub x: usize,
     pub y: usize,
@@ -37,14 +75,32 @@ impl StatusMessage {
     }
 }
 
+#[allow(clippy::struct_excessive_bools)]
 pub struct Editor {
     should_quit: bool,
     terminal: Terminal,
     cursor_position: Position,
+    // Where the selection started; the cursor is its other end.
+    selection_anchor: Option<Position>,
     offset: Position,
+    // With soft wrap on, the screen line of row `offset.y` that is shown at the top.
+    offset_line: usize,
+    soft_wrap: bool,
     document: Document,
     status_message: StatusMessage,
     quit_times: u8,
+    config: Config,
+    pending_swap: Option<(String, PathBuf)>,
+    swap_revision: u64,
+    swap_time: Instant,
+    swap_written: bool,
+    // Set once autosave found a swap file this session didn't write.
+    foreign_swap: bool,
+    disk_change_noticed: bool,
+    search_options: SearchOptions,
+    matches: Option<Matches>,
+    line_numbers: LineNumbers,
+    clipboard: Clipboard,
 }
 
 impl Editor{
@@ -53,17 +109,31 @@ impl Editor{
 
         let _stdout = io::stdout();
 
+        if let Some((file_name, swap_path)) = self.pending_swap.take() {
+            self.offer_recovery(&file_name, &swap_path);
+        }
         loop {
             if let Err(error) = self.refresh_screen(){
                 die(&error)
             }
             if self.should_quit {
+                if self.swap_written {
+                    self.remove_swap();
+                }
                 disable_raw_mode().expect("Failed to disable raw mode");
                 break;
             }
-            if let Err(error) = self.process_keypress(){
-                die(&error)
+            match Terminal::poll(TICK) {
+                Ok(true) => {
+                    if let Err(error) = self.process_keypress(){
+                        die(&error)
+                    }
+                }
+                Ok(false) => (),
+                Err(error) => die(&error),
             }
+            self.autosave();
+            self.check_disk();
         }
 
         
@@ -71,8 +141,15 @@ impl Editor{
 
     pub fn default() -> Self{
         let args: Vec<String> = env::args().collect();
-        let mut initial_status = String::from("HELP: Ctrl-F = find | Ctrl-S = save | Ctrl-Q = quit");
-        let document = if let Some(file_name) = args.get(1) {
+        let (file_name, start_position) = match args.get(1) {
+            Some(arg) => {
+                let (file_name, position) = split_position(arg);
+                (Some(file_name), position)
+            }
+            None => (None, None),
+        };
+        let mut initial_status = String::from("HELP: Ctrl-F = find | Ctrl-S = save | Ctrl-Q = quit | F1 = all keys");
+        let document = if let Some(file_name) = &file_name {
             let doc = Document::open(file_name);
             if let Ok(doc) = doc {
                 doc
@@ -84,31 +161,227 @@ impl Editor{
             Document::default()
         };
 
+        let config = Config::from_env();
+        let pending_swap = file_name.as_ref().and_then(|file_name| {
+            let swap_path = swap::path(file_name, config.swap_dir.as_deref());
+            swap_path.exists().then(|| (file_name.clone(), swap_path))
+        });
+
+        let terminal = Terminal::default().expect("Failed to initialize terminal");
+        let mut editor = Self::new(terminal, document, config);
+        editor.status_message = StatusMessage::from(initial_status);
+        editor.pending_swap = pending_swap;
+        if let Some(position) = start_position {
+            editor.go_to(&position);
+        }
+        editor
+    }
+
+    fn new(terminal: Terminal, document: Document, config: Config) -> Self {
+        let line_numbers = config.line_numbers;
         Self{
             should_quit:false,
-            terminal: Terminal::default().expect("Failed to initialize terminal"),
+            terminal,
             cursor_position: Position::default(),
+            selection_anchor: None,
             offset: Position::default(),
-            document: document,
-            status_message: StatusMessage::from(initial_status),
+            offset_line: 0,
+            soft_wrap: config.soft_wrap,
+            document,
+            status_message: StatusMessage::from(String::new()),
             quit_times: QUIT_TIMES,
+            config,
+            pending_swap: None,
+            swap_revision: 0,
+            swap_time: Instant::now(),
+            swap_written: false,
+            foreign_swap: false,
+            disk_change_noticed: false,
+            search_options: SearchOptions::default(),
+            matches: None,
+            line_numbers,
+            clipboard: Clipboard::default(),
+        }
+    }
+
+    // Reacts to another program changing the file, once per change: a clean buffer is
+    // offered a reload, a dirty one gets a warning and Ctrl-S asks before overwriting.
+    fn check_disk(&mut self) {
+        if self.disk_change_noticed {
+            return;
+        }
+        match self.document.disk_status() {
+            DiskStatus::Unchanged => (),
+            DiskStatus::Deleted => {
+                self.disk_change_noticed = true;
+                self.status_message = StatusMessage::from("WARNING! File was deleted on disk.".to_string());
+            }
+            DiskStatus::Modified if !self.document.is_dirty() => {
+                self.disk_change_noticed = true;
+                let answer = self.prompt_choice("File changed on disk. Reload it? (y/n) ", "yn");
+                if answer.unwrap_or(None) == Some('y') {
+                    self.reload();
+                }
+            }
+            DiskStatus::Modified => {
+                self.disk_change_noticed = true;
+                self.status_message = StatusMessage::from(
+                    "WARNING! File changed on disk. Ctrl-S will ask before overwriting it.".to_string(),
+                );
+            }
+        }
+    }
+
+    fn reload(&mut self) {
+        let Some(file_name) = self.document.file_name.clone() else {
+            return;
+        };
+        match Document::open(&file_name) {
+            Ok(document) => {
+                self.document = document;
+                self.disk_change_noticed = false;
+                self.cursor_position.y = self.cursor_position.y.min(self.document.len());
+                self.move_cursor(KeyCode::Null);
+                self.scroll();
+                self.status_message = StatusMessage::from("Reloaded from disk.".to_string());
+            }
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("Could not reload: {}", error));
+            }
+        }
+    }
+
+    fn swap_path(&self) -> Option<PathBuf> {
+        let file_name = self.document.file_name.as_deref()?;
+        Some(swap::path(file_name, self.config.swap_dir.as_deref()))
+    }
+
+    // Writes unsaved changes to the swap file at most once per autosave interval, and
+    // drops the swap file again once the buffer is clean.
+    fn autosave(&mut self) {
+        let Some(interval) = self.config.autosave else {
+            return;
+        };
+        if !self.document.is_dirty() {
+            if self.swap_written {
+                self.remove_swap();
+            }
+            return;
+        }
+        if self.document.revision() == self.swap_revision || self.swap_time.elapsed() < interval {
+            return;
+        }
+        if let Some(swap_path) = self.swap_path() {
+            if !self.swap_written && swap_path.exists() {
+                // Another session's swap file, or one the user chose to ignore: leave it be.
+                if !self.foreign_swap {
+                    self.foreign_swap = true;
+                    self.status_message = StatusMessage::from(format!(
+                        "Not autosaving: {} belongs to another session.",
+                        swap_path.display()
+                    ));
+                }
+            } else if let Err(error) = swap::write(&swap_path, &self.document.contents()) {
+                self.status_message = StatusMessage::from(format!("Could not write swap file: {}", error));
+            } else {
+                self.swap_written = true;
+            }
+        }
+        self.swap_revision = self.document.revision();
+        self.swap_time = Instant::now();
+    }
+
+    fn remove_swap(&mut self) {
+        if let Some(swap_path) = self.swap_path() {
+            swap::remove(&swap_path);
+        }
+        self.swap_written = false;
+    }
+
+    fn offer_recovery(&mut self, file_name: &str, swap_path: &PathBuf) {
+        loop {
+            let question = format!(
+                "Found swap file {}: (r)ecover, (d)iff, (x) delete, (i)gnore? ",
+                swap_path.display()
+            );
+            match self.prompt_choice(&question, "rdxi").unwrap_or(None) {
+                Some('r') => match Document::recover(file_name, swap_path) {
+                    Ok(document) => {
+                        self.document = document;
+                        // The swap file now holds this session's changes, so it is kept up
+                        // to date and removed once they are saved.
+                        self.swap_written = true;
+                        self.status_message = StatusMessage::from(
+                            "Recovered unsaved changes. Ctrl-S writes them to the file.".to_string(),
+                        );
+                    }
+                    Err(error) => {
+                        self.status_message = StatusMessage::from(format!("Could not recover: {}", error));
+                    }
+                },
+                Some('d') => {
+                    self.show_swap_diff(file_name, swap_path);
+                    continue;
+                }
+                Some('x') => {
+                    swap::remove(swap_path);
+                    self.status_message = StatusMessage::from("Swap file deleted.".to_string());
+                }
+                _ => (),
+            }
+            break;
         }
     }
+
+    fn show_swap_diff(&mut self, file_name: &str, swap_path: &PathBuf) {
+        let on_disk = std::fs::read_to_string(file_name).unwrap_or_default();
+        let swapped = std::fs::read_to_string(swap_path).unwrap_or_default();
+        let diff = swap::diff(&on_disk, &swapped);
+        self.show_page(&diff, "Swap file changes against disk. Press any key to go back.");
+    }
+
+    // Shows `text` in place of the document until a key is pressed.
+    fn show_page(&mut self, text: &str, message: &str) {
+        let document = std::mem::replace(&mut self.document, Document::from(text));
+        let cursor_position = std::mem::take(&mut self.cursor_position);
+        let offset = std::mem::take(&mut self.offset);
+        let offset_line = std::mem::take(&mut self.offset_line);
+        let selection_anchor = self.selection_anchor.take();
+        let _ = self.prompt_choice(message, "");
+        self.document = document;
+        self.cursor_position = cursor_position;
+        self.offset = offset;
+        self.offset_line = offset_line;
+        self.selection_anchor = selection_anchor;
+    }
     fn save(&mut self) {
         if self.document.file_name.is_none() {
-            let new_name = self.prompt("Save as: ", |_, _, _| {}).unwrap_or(None);
+            let new_name = self
+                .prompt("Save as: ", |_, _, _| String::new())
+                .unwrap_or(None)
+                .filter(|name| !name.is_empty());
             if new_name.is_none() {
                 self.status_message = StatusMessage::from("Save aborted.".to_string());
                 return;
             }
             self.document.file_name = new_name;
+        } else if self.document.disk_status() == DiskStatus::Modified {
+            let answer = self.prompt_choice("File changed on disk since it was read. Overwrite it? (y/n) ", "yn");
+            if answer.unwrap_or(None) != Some('y') {
+                self.status_message = StatusMessage::from("Save aborted.".to_string());
+                return;
+            }
         }
 
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from("File saved successfully.".to_string());     
-        } else {
-            self.status_message = StatusMessage::from("Error writing file!".to_string());     
-
+        match self.document.save(self.config.backup) {
+            Ok(()) => {
+                if self.swap_written {
+                    self.remove_swap();
+                }
+                self.disk_change_noticed = false;
+                self.status_message = StatusMessage::from("File saved successfully.".to_string());
+            }
+            Err(error) => self.status_message = StatusMessage::from(format!("Error writing file: {}", error)),
         }
     }
 
@@ -116,11 +389,12 @@ impl Editor{
         let old_position = self.cursor_position.clone();
         let mut direction = SearchDirection::Forward;
         let query = self
-            .prompt("Search (ESC to cancel, Arrows to navigate): ", 
+            .prompt("Search (ESC to cancel, Arrows to navigate, Ctrl-R/E/W = regex/case/word): ", 
             |editor, key_event, query | {
                 let mut moved = false;
                 if key_event.kind==KeyEventKind::Press {
                     match key_event.code {
+                        _ if editor.toggle_search_option(key_event) => (),
                         KeyCode::Right | KeyCode::Down => {
                             direction = SearchDirection::Forward;
                             editor.move_cursor(KeyCode::Right);
@@ -130,27 +404,267 @@ impl Editor{
                         _ => direction = SearchDirection::Forward,
                     }
                 } 
-                if let Some(position) = editor
+                let mode = editor.search_mode_hint();
+                if query.is_empty() {
+                    return mode;
+                }
+                let text = query;
+                let query = match Query::new(query, editor.search_options) {
+                    Ok(query) => query,
+                    Err(error) => {
+                        editor.matches = None;
+                        return format!("{}  invalid pattern: {}", mode, error);
+                    }
+                };
+                if !editor.matches.as_ref().is_some_and(|matches| matches.is_for(text, editor.search_options)) {
+                    let ranges = editor.document.find_all(&query);
+                    editor.matches = Some(Matches::new(text, editor.search_options, ranges));
+                }
+                let mut wrapped = false;
+                let found = editor
                     .document
-                    .find(&query, &editor.cursor_position, direction) 
-                {
+                    .find(&query, &editor.cursor_position, direction)
+                    .or_else(|| {
+                        wrapped = true;
+                        let from = editor.document_boundary(direction);
+                        editor.document.find(&query, &from, direction)
+                    });
+                if let Some(position) = found {
                     editor.cursor_position = position;
                     editor.scroll();
                 } else if moved {
                     editor.move_cursor(KeyCode::Left);
                 }
+                let wrap_notice = if wrapped {
+                    match direction {
+                        SearchDirection::Forward => "  (wrapped to top)",
+                        SearchDirection::Backward => "  (wrapped to bottom)",
+                    }
+                } else {
+                    ""
+                };
+                match &editor.matches {
+                    Some(matches) if matches.len() > 0 => format!(
+                        "{}  match {} of {}{}",
+                        mode,
+                        matches.index_from(&editor.cursor_position).saturating_add(1),
+                        matches.len(),
+                        wrap_notice
+                    ),
+                    _ => format!("{}  no matches", mode),
+                }
             }
         )
-        .unwrap_or(None);
+        .unwrap_or(None)
+        .filter(|query| !query.is_empty());
+        self.matches = None;
         
         if query.is_none() {
             self.cursor_position = old_position;
             self.scroll();
         }
     }
+    // Where a search that ran off one end of the document continues from.
+    fn document_boundary(&self, direction: SearchDirection) -> Position {
+        match direction {
+            SearchDirection::Forward => Position::default(),
+            SearchDirection::Backward => {
+                let y = self.document.len().saturating_sub(1);
+                let x = self.document.row(y).map_or(0, Row::len);
+                Position { x, y }
+            }
+        }
+    }
+    // Handles the keys that switch search modes inside the search and replace prompts.
+    fn toggle_search_option(&mut self, key_event: KeyEvent) -> bool {
+        if !key_event.modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        match key_event.code {
+            KeyCode::Char('r') => self.search_options.regex = !self.search_options.regex,
+            KeyCode::Char('e') => self.search_options.case = self.search_options.case.next(),
+            KeyCode::Char('w') => self.search_options.whole_word = !self.search_options.whole_word,
+            _ => return false,
+        }
+        true
+    }
+
+    fn search_mode_hint(&self) -> String {
+        let mut hint = String::new();
+        if self.search_options.regex {
+            hint.push_str("  [regex]");
+        }
+        match self.search_options.case {
+            CaseMode::Sensitive => (),
+            CaseMode::Insensitive => hint.push_str("  [ignore case]"),
+            CaseMode::Smart => hint.push_str("  [smart case]"),
+        }
+        if self.search_options.whole_word {
+            hint.push_str("  [whole word]");
+        }
+        hint
+    }
+
+    fn replace(&mut self) {
+        let search = self
+            .prompt("Replace (ESC to cancel, Ctrl-R/E/W = regex/case/word): ", |editor, key_event, _| {
+                if key_event.kind == KeyEventKind::Press {
+                    editor.toggle_search_option(key_event);
+                }
+                editor.search_mode_hint()
+            })
+            .unwrap_or(None)
+            .filter(|search| !search.is_empty());
+        let Some(search) = search else {
+            self.status_message = StatusMessage::from("Replace aborted.".to_string());
+            return;
+        };
+        let query = match Query::new(&search, self.search_options) {
+            Ok(query) => query,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("Invalid pattern: {}", error));
+                return;
+            }
+        };
+        let Some(replacement) = self
+            .prompt(&format!("Replace {} with: ", search), |_, _, _| String::new())
+            .unwrap_or(None)
+        else {
+            self.status_message = StatusMessage::from("Replace aborted.".to_string());
+            return;
+        };
+
+        let mut replace_all = false;
+        let mut count: usize = 0;
+        let mut at = self.cursor_position.clone();
+        self.document.begin_undo_group();
+        while let Some((start, end)) = self.document.find_match(&query, &at, SearchDirection::Forward) {
+            self.cursor_position = start.clone();
+            self.scroll();
+            let answer = if replace_all {
+                Some('y')
+            } else {
+                self.prompt_choice("Replace this match? (y)es, (n)o, (a)ll, (q)uit ", "ynaq")
+                    .unwrap_or(None)
+            };
+            match answer {
+                Some('y' | 'a') => {
+                    replace_all = replace_all || answer == Some('a');
+                    let text = self
+                        .document
+                        .row(start.y)
+                        .map(|row| row.expand_replacement(&query, start.x, &replacement))
+                        .unwrap_or_default();
+                    self.document.delete_range(&start, &end);
+                    at = self.document.insert_str(&start, &text);
+                    count = count.saturating_add(1);
+                }
+                Some('n') => at = Position { x: start.x.saturating_add(1), y: start.y },
+                _ => break,
+            }
+        }
+        self.document.end_undo_group();
+        self.cursor_position = at;
+        self.move_cursor(KeyCode::Null);
+        self.scroll();
+        self.status_message = StatusMessage::from(format!("Replaced {} occurrence(s).", count));
+    }
+
+    fn go_to_prompt(&mut self) {
+        let current = self.cursor_position.clone();
+        let len = self.document.len();
+        let input = self
+            .prompt("Go to (line[:column], +/-lines, %percent): ", |_, _, _| String::new())
+            .unwrap_or(None);
+        let Some(input) = input else {
+            return;
+        };
+        if let Some(position) = parse_go_to(&input, &current, len) {
+            self.go_to(&position);
+        } else if !input.trim().is_empty() {
+            self.status_message = StatusMessage::from(format!("Not a line: {}", input));
+        }
+    }
+
+    /// Moves the cursor to `position`, clamped to the document, with its row in the
+    /// middle of the screen.
+    fn go_to(&mut self, position: &Position) {
+        let last = self.document.len().saturating_sub(1);
+        self.cursor_position = Position {
+            x: position.x,
+            y: cmp::min(position.y, last),
+        };
+        self.move_cursor(KeyCode::Null);
+        #[allow(clippy::integer_division)]
+        let half = self.terminal.size().height as usize / 2;
+        self.offset.y = self.cursor_position.y.saturating_sub(half);
+        self.offset_line = 0;
+        self.scroll();
+    }
+
+    fn undo(&mut self) {
+        if let Some(position) = self.document.undo() {
+            self.cursor_position = position;
+        } else {
+            self.status_message = StatusMessage::from("Nothing to undo.".to_string());
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(position) = self.document.redo() {
+            self.cursor_position = position;
+        } else {
+            self.status_message = StatusMessage::from("Nothing to redo.".to_string());
+        }
+    }
+
+    fn toggle_line_ending(&mut self) {
+        let line_ending = match self.document.line_ending() {
+            LineEnding::Lf => LineEnding::CrLf,
+            LineEnding::CrLf | LineEnding::Mixed => LineEnding::Lf,
+        };
+        self.document.set_line_ending(line_ending);
+        self.status_message = StatusMessage::from(format!(
+            "Line endings converted to {}.",
+            line_ending.name()
+        ));
+    }
+
     fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let KeyEvent{code, modifiers, kind, .. } = Terminal::read_key_event()?;
+        let event = self.terminal.read_event()?;
+        self.process_event(event);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn process_event(&mut self, event: Event) {
+        let KeyEvent{code, modifiers, kind, .. } = match event {
+            Event::Key(key_event) => key_event,
+            Event::Resize(..) => {
+                self.resize();
+                return;
+            }
+            Event::Mouse(mouse_event) => {
+                self.process_mouse(mouse_event);
+                return;
+            }
+            Event::Paste(text) => {
+                self.paste(Some(text));
+                self.scroll();
+                return;
+            }
+            _ => return,
+        };
         if kind==KeyEventKind::Press {
+            let movement = matches!(
+                code,
+                KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+                    | KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End
+            );
+            let selecting = movement && modifiers.contains(KeyModifiers::SHIFT);
+            if selecting && self.selection_anchor.is_none() {
+                self.selection_anchor = Some(self.cursor_position.clone());
+            }
             match code {
                 KeyCode::Char('q') if modifiers.contains(KeyModifiers::CONTROL) => {
                     if self.quit_times > 0 && self.document.is_dirty() {
@@ -159,44 +673,532 @@ impl Editor{
                             self.quit_times
                         ));
                         self.quit_times -= 1;
-                        return Ok(());
+                        return;
                     }
                     self.should_quit = true;
                 },
                 KeyCode::Char('s') if modifiers.contains(KeyModifiers::CONTROL) => self.save(),
                 KeyCode::Char('f') if modifiers.contains(KeyModifiers::CONTROL) => self.search(),
-                KeyCode::Char(c) => {
-                    self.document.insert(&self.cursor_position, c);
-                    self.move_cursor(KeyCode::Right);
-                },
-                KeyCode::Enter => {
-                    self.document.insert(&self.cursor_position, '\n');
-                    self.move_cursor(KeyCode::Right);
-                },
-                KeyCode::Delete => self.document.delete(&self.cursor_position),
+                KeyCode::Char('r') if modifiers.contains(KeyModifiers::CONTROL) => self.replace(),
+                KeyCode::Char('z') if modifiers.contains(KeyModifiers::CONTROL) => self.undo(),
+                KeyCode::Char('y') if modifiers.contains(KeyModifiers::CONTROL) => self.redo(),
+                KeyCode::Char('e') if modifiers.contains(KeyModifiers::CONTROL) => self.toggle_line_ending(),
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.copy(None);
+                }
+                KeyCode::Char('x') if modifiers.contains(KeyModifiers::CONTROL) => self.cut(),
+                KeyCode::Char('v') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    let text = self.import_clipboard();
+                    self.paste(text);
+                }
+                KeyCode::Char('k') if modifiers.contains(KeyModifiers::CONTROL) => self.copy_to_register(),
+                KeyCode::Char('p') if modifiers.contains(KeyModifiers::CONTROL) => self.paste_from_register(),
+                KeyCode::Char('g') if modifiers.contains(KeyModifiers::CONTROL) => self.go_to_prompt(),
+                KeyCode::Char('w') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.soft_wrap = !self.soft_wrap;
+                    self.offset.x = 0;
+                    self.offset_line = 0;
+                }
+                KeyCode::Char('n') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.line_numbers = self.line_numbers.next();
+                }
+                KeyCode::F(1) => self.show_page(KEYS, "Keys. Press any key to go back."),
+                // Most terminals send Ctrl-Backspace as Ctrl-H.
+                KeyCode::Char('h') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.delete_word(SearchDirection::Backward);
+                }
+                KeyCode::Char(c) => self.insert_char(c),
+                KeyCode::Enter => self.insert_newline(),
+                KeyCode::Tab => self.insert_tab(),
+                KeyCode::Backspace if modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {
+                    self.delete_word(SearchDirection::Backward);
+                }
+                KeyCode::Delete if modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {
+                    self.delete_word(SearchDirection::Forward);
+                }
+                KeyCode::Delete => {
+                    if !self.delete_selection() {
+                        self.document.delete(&self.cursor_position);
+                    }
+                }
                 KeyCode::Backspace => {
-                    if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
+                    if !self.delete_selection() && (self.cursor_position.x > 0 || self.cursor_position.y > 0) {
                         self.move_cursor(KeyCode::Left);
                         self.document.delete(&self.cursor_position)
                     }
                 }
+                // Without Shift, Left and Right first collapse a selection onto its edge.
+                KeyCode::Left | KeyCode::Right if !selecting && self.selection().is_some() => {
+                    if let Some((start, end)) = self.selection() {
+                        self.cursor_position = if code == KeyCode::Left { start } else { end };
+                    }
+                }
+                KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right | KeyCode::Home | KeyCode::End
+                    if modifiers.contains(KeyModifiers::CONTROL) =>
+                {
+                    self.cursor_position = self.jump_target(code);
+                }
                 KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right => self.move_cursor(code),
                 KeyCode::PageUp | KeyCode::PageDown | KeyCode::End | KeyCode::Home => self.move_cursor(code),
                 _ => ()
             }
+            if !selecting {
+                self.selection_anchor = None;
+            }
         }
         self.scroll();
         if self.quit_times < QUIT_TIMES {
             self.quit_times = QUIT_TIMES;
             self.status_message = StatusMessage::from(String::new());
         }
-        Ok(())
     }
 
+    fn process_mouse(&mut self, mouse_event: MouseEvent) {
+        let MouseEvent { kind, column, row, .. } = mouse_event;
+        match kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if let Some(position) = self.screen_to_document(column, row) {
+                    self.cursor_position = position;
+                    self.selection_anchor = None;
+                }
+            }
+            // A click only places the cursor; the selection starts there once the mouse
+            // moves with the button held.
+            MouseEventKind::Drag(MouseButton::Left) => {
+                if let Some(position) = self.screen_to_document(column, row) {
+                    if self.selection_anchor.is_none() {
+                        self.selection_anchor = Some(self.cursor_position.clone());
+                    }
+                    self.cursor_position = position;
+                    self.scroll();
+                }
+            }
+            MouseEventKind::ScrollUp => self.scroll_view(SearchDirection::Backward),
+            MouseEventKind::ScrollDown => self.scroll_view(SearchDirection::Forward),
+            _ => (),
+        }
+    }
+
+    /// The document position shown at a screen cell, clamped to the text; `None` for
+    /// cells outside the text area, such as the status and message bars.
+    fn screen_to_document(&self, column: u16, row: u16) -> Option<Position> {
+        // Text starts on the second screen line, see `Terminal::cursor_position`.
+        let row = (row as usize).checked_sub(1)?;
+        if row >= self.terminal.size().height as usize {
+            return None;
+        }
+        let column = (column as usize).saturating_sub(self.gutter_width());
+        if self.soft_wrap {
+            let mut line = (self.offset.y, self.offset_line);
+            for _ in 0..row {
+                match self.next_line(line) {
+                    Some(next) => line = next,
+                    None => break,
+                }
+            }
+            return Some(self.position_on_line(line, column));
+        }
+        let y = cmp::min(self.offset.y.saturating_add(row), self.document.len());
+        let column = self.offset.x.saturating_add(column);
+        let x = self
+            .document
+            .row(y)
+            .map_or(0, |row| row.index_at_column(column, self.config.tab_width));
+        Some(Position { x, y })
+    }
+
+    // Moves the viewport without moving the cursor, unless the cursor would end up
+    // outside it.
+    fn scroll_view(&mut self, direction: SearchDirection) {
+        let height = self.terminal.size().height as usize;
+        let last = self.document.len().saturating_sub(1);
+        let mut top = (self.offset.y, self.offset_line);
+        for _ in 0..WHEEL_LINES {
+            let line = match direction {
+                SearchDirection::Forward => self.next_line(top).filter(|(y, _)| *y <= last),
+                SearchDirection::Backward => self.previous_line(top),
+            };
+            match line {
+                Some(line) => top = line,
+                None => break,
+            }
+        }
+        self.offset.y = top.0;
+        self.offset_line = top.1;
+        let mut bottom = top;
+        for _ in 1..height {
+            match self.next_line(bottom) {
+                Some(line) => bottom = line,
+                None => break,
+            }
+        }
+        let (cursor, column) = self.cursor_line();
+        let line = cursor.clamp(top, bottom);
+        if line != cursor {
+            self.cursor_position = self.position_on_line(line, column);
+        }
+    }
+
+    // Typing replaces the selection, if there is one.
+    fn insert_char(&mut self, c: char) {
+        if self.auto_indent() && self.selection().is_none() && self.dedent(c) {
+            return;
+        }
+        if let Some((start, end)) = self.selection() {
+            self.cursor_position = self.document.replace_range(&start, &end, &c.to_string());
+            self.selection_anchor = None;
+            return;
+        }
+        self.document.insert(&self.cursor_position, c);
+        self.move_cursor(KeyCode::Right);
+    }
+
+    /// Copies the selection to the clipboard history and, if given, a named register.
+    fn copy(&mut self, register: Option<char>) -> bool {
+        let Some((start, end)) = self.selection() else {
+            self.status_message = StatusMessage::from("Nothing selected.".to_string());
+            return false;
+        };
+        let text = self.document.text_range(&start, &end);
+        self.clipboard.yank(register, &text);
+        if register.is_none() {
+            self.export_clipboard(&text);
+        }
+        true
+    }
+
+    // Copies also go to the system clipboard: through the configured helper command,
+    // and with OSC 52 through the terminal, which works over SSH too.
+    fn export_clipboard(&mut self, text: &str) {
+        if let Some(command) = &self.config.copy_command {
+            if let Err(error) = clipboard::copy_with(command, text) {
+                self.status_message = StatusMessage::from(format!("ERR: Copy failed: {}", error));
+            }
+        }
+        if self.config.osc52 {
+            if let Err(error) = Terminal::copy_to_clipboard(text) {
+                self.status_message = StatusMessage::from(format!("ERR: Copy to terminal failed: {}", error));
+            }
+        }
+    }
+
+    // With a paste helper configured, Ctrl-V pastes the system clipboard; the internal
+    // one is used when there is no helper or it fails.
+    fn import_clipboard(&mut self) -> Option<String> {
+        if let Some(command) = &self.config.paste_command {
+            match clipboard::paste_with(command) {
+                Ok(text) => return Some(text),
+                Err(error) => {
+                    self.status_message = StatusMessage::from(format!("ERR: Paste failed: {}", error));
+                }
+            }
+        }
+        self.clipboard.latest().map(str::to_string)
+    }
+
+    fn cut(&mut self) {
+        if self.copy(None) {
+            self.delete_selection();
+        }
+    }
+
+    fn paste(&mut self, text: Option<String>) {
+        match text {
+            Some(text) => self.insert_text(&text),
+            None => self.status_message = StatusMessage::from("Nothing to paste.".to_string()),
+        }
+    }
+
+    // Auto-indent is on, and not turned off for the file's language.
+    fn auto_indent(&self) -> bool {
+        self.config.auto_indent && self.config.indent(&self.document.file_type()) != Some(Indent::Off)
+    }
+
+    /// One level of indentation: a tab or spaces, as the line is already indented. An
+    /// unindented line follows most of the file, and only then do the language's
+    /// `HECTO_INDENT_*` setting, `expand_tabs` and the language's own default decide.
+    fn indent_unit(&self, indentation: &str) -> String {
+        let language = self.config.indent(&self.document.file_type());
+        let width = match language {
+            Some(Indent::Spaces(width)) => width,
+            _ => self.document.indent_options().width(),
+        };
+        let tabs = if indentation.starts_with('\t') {
+            true
+        } else if indentation.starts_with(' ') {
+            false
+        } else if let Some(tabs) = self.document.indents_with_tabs() {
+            tabs
+        } else {
+            match language {
+                Some(Indent::Tabs) => true,
+                Some(Indent::Spaces(_)) => false,
+                _ => !self.config.expand_tabs && width == 0,
+            }
+        };
+        if tabs {
+            return "\t".to_string();
+        }
+        " ".repeat(if width == 0 { self.config.tab_width } else { width })
+    }
+
+    // Enter keeps the indentation of the current line and adds a level after an opening
+    // bracket (or `:` in Python). Between a pair like `{}` the closing one goes on a
+    // line of its own below the cursor.
+    fn insert_newline(&mut self) {
+        // A selection is replaced by the line break, so the text before it and after it
+        // are what ends up on either side.
+        let (start, end) = self
+            .selection()
+            .unwrap_or_else(|| (self.cursor_position.clone(), self.cursor_position.clone()));
+        let Position { x, y } = start;
+        let Some(row) = self.document.row(y).filter(|_| self.auto_indent()) else {
+            self.insert_char('\n');
+            return;
+        };
+        let indentation = row.indentation();
+        let indentation = &indentation[..indentation.len().min(row.substring(0, x).len())];
+        let before = row.substring(0, x).trim_end().chars().last();
+        let after = self
+            .document
+            .row(end.y)
+            .and_then(|row| row.substring(end.x, row.len()).trim_start().chars().next());
+        let options = self.document.indent_options();
+        let opens = before.is_some_and(|c| options.openers().contains(&c));
+        let closes = after.is_some_and(|c| options.closers().contains(&c));
+        let mut text = format!("\n{}", indentation);
+        if opens {
+            text.push_str(&self.indent_unit(indentation));
+            if closes {
+                let position = text.chars().count();
+                text.push('\n');
+                text.push_str(indentation);
+                self.insert_text(&text);
+                self.cursor_position = Position {
+                    x: position.saturating_sub(1),
+                    y: y.saturating_add(1),
+                };
+                return;
+            }
+        }
+        self.insert_text(&text);
+    }
+
+    // A closing bracket typed into the indentation at the start of a line takes one
+    // level of indentation away. Returns whether it did.
+    fn dedent(&mut self, c: char) -> bool {
+        let Position { x, y } = self.cursor_position.clone();
+        if !self.document.indent_options().closers().contains(&c) {
+            return false;
+        }
+        let Some(row) = self.document.row(y) else {
+            return false;
+        };
+        let indentation = row.substring(0, x);
+        if indentation.is_empty() || !indentation.trim().is_empty() {
+            return false;
+        }
+        let unit = self.indent_unit(&indentation);
+        let kept = if indentation.ends_with('\t') {
+            &indentation[..indentation.len().saturating_sub(1)]
+        } else {
+            let spaces = indentation.len().saturating_sub(indentation.trim_end_matches(' ').len());
+            &indentation[..indentation.len().saturating_sub(spaces.min(unit.len()))]
+        };
+        let text = format!("{}{}", kept, c);
+        self.cursor_position =
+            self.document
+                .replace_range(&Position { x: 0, y }, &Position { x, y }, &text);
+        true
+    }
+
+    // A tab character, or with `expand_tabs` as many spaces as reach the next tab stop.
+    fn insert_tab(&mut self) {
+        if !self.config.expand_tabs {
+            self.insert_char('\t');
+            return;
+        }
+        let tab_width = self.config.tab_width;
+        let column = self
+            .selection()
+            .map_or_else(|| self.cursor_column(), |(start, _)| {
+                self.document.row(start.y).map_or(0, |row| row.column(start.x, tab_width))
+            });
+        #[allow(clippy::integer_arithmetic)]
+        let spaces = tab_width - column % tab_width;
+        self.insert_text(&" ".repeat(spaces));
+    }
+
+    /// Inserts `text` at the cursor, in place of the selection if there is one, and
+    /// leaves the cursor after it.
+    fn insert_text(&mut self, text: &str) {
+        self.cursor_position = if let Some((start, end)) = self.selection() {
+            self.document.replace_range(&start, &end, text)
+        } else {
+            self.document.insert_str(&self.cursor_position, text)
+        };
+        self.selection_anchor = None;
+    }
+
+    fn copy_to_register(&mut self) {
+        if self.selection().is_none() {
+            self.status_message = StatusMessage::from("Nothing selected.".to_string());
+            return;
+        }
+        let answer = self.prompt_choice("Copy to register (a-z): ", REGISTERS);
+        if let Some(register) = answer.unwrap_or(None) {
+            self.copy(Some(register));
+            self.status_message = StatusMessage::from(format!("Copied to register {}.", register));
+        }
+    }
+
+    fn paste_from_register(&mut self) {
+        let choices = format!("{}{}", REGISTERS, HISTORY_REGISTERS);
+        let answer = self.prompt_choice("Paste from register (a-z, 0-9 for recent copies): ", &choices);
+        if let Some(register) = answer.unwrap_or(None) {
+            let text = self.clipboard.get(register).map(str::to_string);
+            self.paste(text);
+        }
+    }
+
+    /// Where Ctrl with `key_code` moves the cursor: Left and Right go by word, Up and
+    /// Down to the blank line before or after the paragraph, Home and End to the start
+    /// and end of the document.
+    fn jump_target(&self, key_code: KeyCode) -> Position {
+        let Position { mut x, mut y } = self.cursor_position;
+        let len = self.document.len();
+        let is_blank = |y: usize| self.document.row(y).is_none_or(Row::is_blank);
+        match key_code {
+            KeyCode::Left => {
+                if x > 0 {
+                    x = self.document.row(y).map_or(0, |row| row.previous_word_start(x));
+                } else if y > 0 {
+                    y = y.saturating_sub(1);
+                    x = self.document.row(y).map_or(0, Row::len);
+                }
+            }
+            KeyCode::Right => {
+                let width = self.document.row(y).map_or(0, Row::len);
+                if x < width {
+                    x = self.document.row(y).map_or(0, |row| row.next_word_end(x));
+                } else if y < len {
+                    y = y.saturating_add(1);
+                    x = 0;
+                }
+            }
+            KeyCode::Up => {
+                while y > 0 && is_blank(y) {
+                    y = y.saturating_sub(1);
+                }
+                while y > 0 && !is_blank(y) {
+                    y = y.saturating_sub(1);
+                }
+                x = 0;
+            }
+            KeyCode::Down => {
+                while y < len && is_blank(y) {
+                    y = y.saturating_add(1);
+                }
+                while y < len && !is_blank(y) {
+                    y = y.saturating_add(1);
+                }
+                x = 0;
+            }
+            KeyCode::Home => return Position::default(),
+            KeyCode::End => {
+                y = len.saturating_sub(1);
+                x = self.document.row(y).map_or(0, Row::len);
+            }
+            _ => (),
+        }
+        Position { x, y }
+    }
+
+    // Deletes from the cursor to the start or end of a word, or the selection if any.
+    fn delete_word(&mut self, direction: SearchDirection) {
+        if self.delete_selection() {
+            return;
+        }
+        match direction {
+            SearchDirection::Backward => {
+                let start = self.jump_target(KeyCode::Left);
+                self.document.delete_range(&start, &self.cursor_position);
+                self.cursor_position = start;
+            }
+            SearchDirection::Forward => {
+                let end = self.jump_target(KeyCode::Right);
+                self.document.delete_range(&self.cursor_position, &end);
+            }
+        }
+    }
+
+    /// Deletes the selected text and puts the cursor where it was; `false` when nothing
+    /// is selected.
+    fn delete_selection(&mut self) -> bool {
+        let Some((start, end)) = self.selection() else {
+            return false;
+        };
+        self.document.delete_range(&start, &end);
+        self.cursor_position = start;
+        self.selection_anchor = None;
+        true
+    }
+
+    /// The selected range in document order, or `None` when nothing is selected.
+    fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.selection_anchor.clone()?;
+        let cursor = self.cursor_position.clone();
+        if anchor == cursor {
+            return None;
+        }
+        if (anchor.y, anchor.x) < (cursor.y, cursor.x) {
+            Some((anchor, cursor))
+        } else {
+            Some((cursor, anchor))
+        }
+    }
+
+    // The old frame was drawn for the old size, and what's left of it would stay on
+    // screen where the new frame doesn't reach.
+    fn resize(&mut self) {
+        Terminal::clear_screen();
+        self.scroll();
+    }
+
+    /// Columns taken by the line numbers left of the text, including the space after
+    /// them; the gutter grows with the number of digits in the last line number.
+    fn gutter_width(&self) -> usize {
+        if self.line_numbers == LineNumbers::Off {
+            return 0;
+        }
+        self.document.len().max(1).to_string().len().saturating_add(1)
+    }
+
+    /// Columns left for the text itself.
+    fn text_width(&self) -> usize {
+        (self.terminal.size().width as usize).saturating_sub(self.gutter_width())
+    }
+
+    /// The screen column of the cursor within its row, before scrolling.
+    fn cursor_column(&self) -> usize {
+        let Position { x, y } = self.cursor_position;
+        self.document.row(y).map_or(0, |row| row.column(x, self.config.tab_width))
+    }
+
+    // `offset.x` counts screen columns, so that a wide character under the cursor is
+    // scrolled fully into view.
     fn scroll(&mut self) {
+        if self.soft_wrap {
+            self.scroll_wrapped();
+            return;
+        }
         let Position { x, y } = self.cursor_position;
-        let width = self.terminal.size().width as usize;
+        let width = self.text_width();
         let height = self.terminal.size().height as usize;
+        let column = self.cursor_column();
+        let cursor_width = self.document.row(y).map_or(1, |row| {
+            row.column(x.saturating_add(1), self.config.tab_width).saturating_sub(column).max(1)
+        });
         let offset = &mut self.offset;
         if y < offset.y {
             offset.y = y;
@@ -204,14 +1206,129 @@ impl Editor{
             offset.y = y.saturating_sub(height).saturating_add(1);
         }
 
-        if x < offset.x {
-            offset.x = x;
-        } else if x >= offset.x.saturating_add(width) {
-            offset.x = x.saturating_sub(width).saturating_add(1);
+        if column < offset.x {
+            offset.x = column;
+        } else if column.saturating_add(cursor_width) > offset.x.saturating_add(width) {
+            offset.x = column.saturating_add(cursor_width).saturating_sub(width);
         }
     }
 
-    fn refresh_screen(&self) -> Result<(), std::io::Error> {
+    // Like `scroll`, but counting screen lines: the top of the screen is a screen line of
+    // a row, and the cursor's screen line must be at most `height - 1` lines below it.
+    fn scroll_wrapped(&mut self) {
+        let height = self.terminal.size().height as usize;
+        let Position { x, y } = self.cursor_position;
+        let cursor = (y, line_of(&self.line_starts(y), x));
+        let last_line = self.line_starts(self.offset.y).len().saturating_sub(1);
+        let mut top = (self.offset.y, cmp::min(self.offset_line, last_line));
+        if cursor < top {
+            top = cursor;
+        } else {
+            let mut lowest_top = cursor;
+            for _ in 1..height {
+                match self.previous_line(lowest_top) {
+                    Some(line) => lowest_top = line,
+                    None => break,
+                }
+            }
+            top = cmp::max(top, lowest_top);
+        }
+        self.offset = Position { x: 0, y: top.0 };
+        self.offset_line = top.1;
+    }
+
+    /// Where each screen line of row `y` starts; a single line unless soft wrap is on.
+    fn line_starts(&self, y: usize) -> Vec<usize> {
+        match self.document.row(y) {
+            Some(row) if self.soft_wrap => row.wrap(cmp::max(self.text_width(), 1), self.config.tab_width),
+            _ => vec![0],
+        }
+    }
+
+    /// The screen line after `(row, line)`; the row after the last is the empty one the
+    /// cursor can move to.
+    fn next_line(&self, (y, line): (usize, usize)) -> Option<(usize, usize)> {
+        if line.saturating_add(1) < self.line_starts(y).len() {
+            Some((y, line.saturating_add(1)))
+        } else if y < self.document.len() {
+            Some((y.saturating_add(1), 0))
+        } else {
+            None
+        }
+    }
+
+    fn previous_line(&self, (y, line): (usize, usize)) -> Option<(usize, usize)> {
+        if line > 0 {
+            Some((y, line.saturating_sub(1)))
+        } else if y > 0 {
+            let y = y.saturating_sub(1);
+            Some((y, self.line_starts(y).len().saturating_sub(1)))
+        } else {
+            None
+        }
+    }
+
+    /// The position `column` screen columns into screen line `line` of row `y`, kept on
+    /// that screen line.
+    fn position_on_line(&self, (y, line): (usize, usize), column: usize) -> Position {
+        let Some(row) = self.document.row(y) else {
+            return Position { x: 0, y };
+        };
+        let tab_width = self.config.tab_width;
+        let starts = self.line_starts(y);
+        let start = starts.get(line).copied().unwrap_or(0);
+        let mut x = row.index_at_column(row.column(start, tab_width).saturating_add(column), tab_width);
+        if let Some(next) = starts.get(line.saturating_add(1)) {
+            x = cmp::min(x, next.saturating_sub(1));
+        }
+        Position { x, y }
+    }
+
+    /// The cursor's screen line and its column on that line.
+    fn cursor_line(&self) -> ((usize, usize), usize) {
+        let Position { x, y } = self.cursor_position;
+        let starts = self.line_starts(y);
+        let line = line_of(&starts, x);
+        let start = starts.get(line).copied().unwrap_or(0);
+        let start_column = self
+            .document
+            .row(y)
+            .map_or(0, |row| row.column(start, self.config.tab_width));
+        ((y, line), self.cursor_column().saturating_sub(start_column))
+    }
+
+    // Where the terminal cursor goes: the screen lines from the top of the screen to the
+    // cursor's, and its column past the gutter.
+    fn cursor_screen_position(&self) -> Position {
+        let gutter_width = self.gutter_width();
+        if !self.soft_wrap {
+            return Position {
+                x: self
+                    .cursor_column()
+                    .saturating_sub(self.offset.x)
+                    .saturating_add(gutter_width),
+                y: self.cursor_position.y.saturating_sub(self.offset.y),
+            };
+        }
+        let (cursor, column) = self.cursor_line();
+        let mut line = (self.offset.y, self.offset_line);
+        let mut y: usize = 0;
+        while line < cursor {
+            match self.next_line(line) {
+                Some(next) => line = next,
+                None => break,
+            }
+            y = y.saturating_add(1);
+        }
+        Position {
+            x: column.saturating_add(gutter_width),
+            y,
+        }
+    }
+
+    fn refresh_screen(&mut self) -> Result<(), std::io::Error> {
+        self.document
+            .highlight(self.offset.y.saturating_add(self.terminal.size().height as usize));
         Terminal::cursor_hide();
         Terminal::cursor_position(&Position::default());
         if self.should_quit {
@@ -221,10 +1338,7 @@ impl Editor{
             self.draw_rows();
             self.draw_status_bar();
             self.draw_message_bar();
-            Terminal::cursor_position(&Position { 
-                x: self.cursor_position.x.saturating_sub(self.offset.x),
-                y: self.cursor_position.y.saturating_sub(self.offset.y), 
-            });
+            Terminal::cursor_position(&self.cursor_screen_position());
 
         }
         Terminal::cursor_show();
@@ -232,9 +1346,23 @@ impl Editor{
     }
 
     fn move_cursor(&mut self, key_code: KeyCode) {
+        // With soft wrap on, Up and Down move by screen line.
+        if self.soft_wrap && matches!(key_code, KeyCode::Up | KeyCode::Down) {
+            let (line, column) = self.cursor_line();
+            let target = if key_code == KeyCode::Up {
+                self.previous_line(line)
+            } else {
+                self.next_line(line)
+            };
+            if let Some(target) = target {
+                self.cursor_position = self.position_on_line(target, column);
+            }
+            return;
+        }
         let terminal_height = self.terminal.size().height as usize;
         let Position { mut y, mut x } = self.cursor_position;
         let height = self.document.len();
+        let column = self.cursor_column();
         let mut width = if let Some(row) = self.document.row(y) {
             row.len()
         } else {
@@ -285,6 +1413,13 @@ impl Editor{
             KeyCode::End => x = width,
             _ => (),
         }
+        // Moving between rows keeps the screen column rather than the grapheme count.
+        if matches!(key_code, KeyCode::Up | KeyCode::Down | KeyCode::PageUp | KeyCode::PageDown) {
+            x = self
+                .document
+                .row(y)
+                .map_or(0, |row| row.index_at_column(column, self.config.tab_width));
+        }
         width = if let Some(row) = self.document.row(y) {
             row.len()
         } else {
@@ -305,27 +1440,84 @@ impl Editor{
         let padding = width.saturating_sub(len) / 2;
         let spaces = " ".repeat(padding.saturating_sub(1));
         welcome_message = format!("~{}{}", spaces, welcome_message);
-        welcome_message.truncate(width);
+        truncate_to_width(&mut welcome_message, width);
         println!("{}\r", welcome_message);
     }
-    pub fn draw_row(&self, row: &Row) {
-        let width = self.terminal.size().width as usize;
-        let start = self.offset.x;
-        let end = self.offset.x.saturating_add(width);
-        let row = row.render(start, end);
-        println!("{}\r", row)
+    fn draw_gutter(&self, index: usize) -> String {
+        let gutter_width = self.gutter_width();
+        if gutter_width == 0 {
+            return String::new();
+        }
+        let cursor_y = self.cursor_position.y;
+        let number = match self.line_numbers {
+            LineNumbers::Relative if index != cursor_y => index.abs_diff(cursor_y),
+            _ => index.saturating_add(1),
+        };
+        format!(
+            "{}{:>width$} {}",
+            SetForegroundColor(GUTTER_FG_COLOR),
+            number,
+            ResetColor,
+            width = gutter_width.saturating_sub(1)
+        )
+    }
+    /// Draws screen columns `start..end` of the row; only the first screen line of a
+    /// wrapped row gets a line number.
+    pub fn draw_row(&self, row: &Row, index: usize, start: usize, end: usize, first_line: bool) {
+        let selection = self
+            .selection()
+            .filter(|(start, end)| (start.y..=end.y).contains(&index))
+            .map(|(start, end)| {
+                let from = if start.y == index { start.x } else { 0 };
+                let to = if end.y == index { end.x } else { row.len() };
+                (from, to, highlighting::Type::Selection)
+            });
+        let matches = self
+            .matches
+            .iter()
+            .flat_map(|matches| matches.in_row(index))
+            .map(|(match_start, match_end)| {
+                let current = self.cursor_position.y == index && self.cursor_position.x == match_start.x;
+                let hl_type = if current {
+                    highlighting::Type::CurrentMatch
+                } else {
+                    highlighting::Type::Match
+                };
+                (match_start.x, match_end.x, hl_type)
+            });
+        let overlay: Vec<(usize, usize, highlighting::Type)> = selection.into_iter().chain(matches).collect();
+        let row = row.render(start, end, &overlay, self.config.tab_width);
+        let gutter = if first_line {
+            self.draw_gutter(index)
+        } else {
+            " ".repeat(self.gutter_width())
+        };
+        println!("{}{}\r", gutter, row)
         }
         
     #[allow(clippy::integer_division, clippy::integer_arithmetic)]
     fn draw_rows(&self) {
         let height = self.terminal.size().height;
+        let width = self.text_width();
+        let mut line = Some((self.offset.y, self.offset_line));
         for terminal_row in 0..height {
             Terminal::clear_current_line();
-            if let Some(row)  = self
-                .document
-                .row(self.offset.y.saturating_add(terminal_row as usize)) 
-            {
-                self.draw_row(row);
+            let (index, line_index) = line.unwrap_or((self.document.len(), 0));
+            line = line.and_then(|line| self.next_line(line));
+            if let Some(row)  = self.document.row(index) {
+                if self.soft_wrap {
+                    let starts = self.line_starts(index);
+                    let tab_width = self.config.tab_width;
+                    let start = row.column(starts.get(line_index).copied().unwrap_or(0), tab_width);
+                    let end = starts
+                        .get(line_index.saturating_add(1))
+                        .map_or(usize::MAX, |next| row.column(*next, tab_width));
+                    let end = cmp::min(end, start.saturating_add(width));
+                    self.draw_row(row, index, start, end, line_index == 0);
+                } else {
+                    let start = self.offset.x;
+                    self.draw_row(row, index, start, start.saturating_add(width), true);
+                }
             } else if self.document.is_empty() && terminal_row == height / 3 {
                 self.draw_welcome_msg();
             } else {
@@ -345,20 +1537,28 @@ impl Editor{
         let mut file_name = "[No Name]".to_string();
         if let Some(name) = &self.document.file_name {
             file_name = name.clone();
-            file_name.truncate(20);
+            truncate_to_width(&mut file_name, 20);
         }
         status = format!("{} - {} lines{}", file_name, self.document.len(), modified_indicator);
         
+        let bom_indicator = if self.document.has_bom() {
+            " BOM"
+        } else {
+            ""
+        };
         let line_indicator = format!(
-            "{}/{}",
+            "{} | {}{} | {}/{}",
+            self.document.file_type(),
+            self.document.line_ending().name(),
+            bom_indicator,
             self.cursor_position.y.saturating_add(1),
             self.document.len(),
         );
         #[allow(clippy::integer_arithmetic)]
-        let len = status.len() + line_indicator.len();
+        let len = status.width() + line_indicator.width();
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, line_indicator);
-        status.truncate(width);
+        truncate_to_width(&mut status, width);
         Terminal::set_bg_color(STATUS_BG_COLOR);
         Terminal::set_fg_color(STATUS_FG_COLOR);
         println!("{}\r", status);
@@ -370,44 +1570,245 @@ impl Editor{
         let message = &self.status_message;
         if Instant::now() - message.time < Duration::new(5, 0) {
             let mut text = message.text.clone();
-            text.truncate(self.terminal.size().width as usize);
+            truncate_to_width(&mut text, self.terminal.size().width as usize);
             print!("{}", text)
         }
     }
+    /// Reads a line of input in the message bar; `None` means the user pressed Esc.
+    /// `callback` runs after every key press (and once when the prompt opens) and returns
+    /// a hint shown after the input.
     fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>, std::io::Error> 
     where
-        C: FnMut(&mut Self, KeyEvent, &String),
+        C: FnMut(&mut Self, KeyEvent, &String) -> String,
     {
         let mut result = String::new();
+        let mut cancelled = false;
+        let mut hint = callback(self, KeyEvent::from(KeyCode::Null), &result);
         loop {
-            self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
+            self.status_message = StatusMessage::from(format!("{}{}{}", prompt, result, hint));
             self.refresh_screen()?;
-            let key_event = Terminal::read_key_event()?; 
+            let key_event = match self.terminal.read_event()? {
+                Event::Key(key_event) => key_event,
+                Event::Resize(..) => {
+                    self.resize();
+                    continue;
+                }
+                // The prompt is a single line, so only the first line of a paste goes in.
+                Event::Paste(text) => {
+                    result.push_str(text.lines().next().unwrap_or_default());
+                    hint = callback(self, KeyEvent::from(KeyCode::Null), &result);
+                    continue;
+                }
+                _ => continue,
+            };
             if key_event.kind == KeyEventKind::Press { 
                 match key_event.code {
-                    KeyCode::Backspace => result.truncate(result.len().saturating_sub(1)),
+                    KeyCode::Backspace => {
+                        result.pop();
+                    },
                     KeyCode::Enter => break,
-                    KeyCode::Char(c) => {
+                    KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => {
                         result.push(c);
                     },
                     KeyCode::Esc => {
                         result.truncate(0);
+                        cancelled = true;
                         break;
                     }
                     _ => ()
                 }
-                callback(self, key_event, &result);
+                hint = callback(self, key_event, &result);
             }
         }
         self.status_message = StatusMessage::from(String::new());
-        if result.is_empty() {
+        if cancelled {
             return Ok(None);
         }
         Ok(Some(result))
     }
+    /// Asks a question answered with a single key out of `choices`. Esc answers `None`,
+    /// and so does any key when there are no choices.
+    fn prompt_choice(&mut self, prompt: &str, choices: &str) -> Result<Option<char>, std::io::Error> {
+        self.status_message = StatusMessage::from(prompt.to_string());
+        let answer = loop {
+            self.refresh_screen()?;
+            let key_event = match self.terminal.read_event()? {
+                Event::Key(key_event) => key_event,
+                Event::Resize(..) => {
+                    self.resize();
+                    continue;
+                }
+                _ => continue,
+            };
+            if key_event.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key_event.code {
+                KeyCode::Esc => break None,
+                _ if choices.is_empty() => break None,
+                KeyCode::Char(c) if choices.contains(c.to_ascii_lowercase()) => {
+                    break Some(c.to_ascii_lowercase());
+                }
+                _ => (),
+            }
+        };
+        self.status_message = StatusMessage::from(String::new());
+        Ok(answer)
+    }
+}
+
+/// Where a go-to prompt input points: `line`, `line:column`, `+lines` or `-lines`
+/// relative to the cursor, or `%percent` of the way through the document. Lines and
+/// columns count from 1.
+fn parse_go_to(input: &str, current: &Position, len: usize) -> Option<Position> {
+    let input = input.trim();
+    if let Some(percent) = input.strip_prefix('%') {
+        let percent: usize = percent.trim().parse().ok()?;
+        let last = len.saturating_sub(1);
+        #[allow(clippy::integer_division)]
+        let y = last.saturating_mul(cmp::min(percent, 100)) / 100;
+        return Some(Position { x: 0, y });
+    }
+    if let Some(lines) = input.strip_prefix('+') {
+        let lines: usize = lines.trim().parse().ok()?;
+        return Some(Position { x: 0, y: current.y.saturating_add(lines) });
+    }
+    if let Some(lines) = input.strip_prefix('-') {
+        let lines: usize = lines.trim().parse().ok()?;
+        return Some(Position { x: 0, y: current.y.saturating_sub(lines) });
+    }
+    let (line, column) = match input.split_once(':') {
+        Some((line, column)) => (line, Some(column)),
+        None => (input, None),
+    };
+    let y = line.trim().parse::<usize>().ok()?.saturating_sub(1);
+    let x = match column {
+        Some(column) => column.trim().parse::<usize>().ok()?.saturating_sub(1),
+        None => 0,
+    };
+    Some(Position { x, y })
+}
+
+// Splits a `file:line` or `file:line:column` argument, as printed by compilers and grep,
+// into the file name and the position. A file whose name really ends like that wins.
+fn split_position(arg: &str) -> (String, Option<Position>) {
+    if Path::new(arg).exists() {
+        return (arg.to_string(), None);
+    }
+    let mut parts = arg.rsplitn(3, ':');
+    let last = parts.next().unwrap_or_default();
+    let middle = parts.next();
+    let rest = parts.next();
+    let is_number = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    let (file_name, position) = match (rest, middle) {
+        (Some(file_name), Some(line)) if is_number(line) && is_number(last) => {
+            (file_name.to_string(), format!("{}:{}", line, last))
+        }
+        (_, Some(_)) if is_number(last) => {
+            let file_name = arg.rsplit_once(':').map_or(arg, |(file_name, _)| file_name);
+            (file_name.to_string(), last.to_string())
+        }
+        _ => return (arg.to_string(), None),
+    };
+    (file_name, parse_go_to(&position, &Position::default(), usize::MAX))
+}
+
+// The screen line of a wrapped row that grapheme `x` is on, given where the lines start.
+fn line_of(starts: &[usize], x: usize) -> usize {
+    starts.partition_point(|start| *start <= x).saturating_sub(1)
+}
+
+// Cuts `text` down to what fits in `width` screen columns, never inside a character.
+fn truncate_to_width(text: &mut String, width: usize) {
+    let mut used: usize = 0;
+    let end = text
+        .grapheme_indices(true)
+        .find(|(_, grapheme)| {
+            used = used.saturating_add(row::grapheme_width(grapheme));
+            used > width
+        })
+        .map_or(text.len(), |(index, _)| index);
+    text.truncate(end);
 }
 
 fn die(e: &std::io::Error) {
     Terminal::clear_screen();
     panic!("{}", e);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(contents: &str) -> Editor {
+        Editor::new(Terminal::with_size(40, 13), Document::from(contents), Config::from_env())
+    }
+
+    // An editor on a file called `name`, so the language comes from its extension.
+    fn editor_for(name: &str, contents: &str) -> Editor {
+        let path = env::temp_dir().join(format!("hecto-editor-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let document = Document::open(&path.to_string_lossy()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        Editor::new(Terminal::with_size(40, 13), document, Config::from_env())
+    }
+
+    fn enter_at_end(editor: &mut Editor) -> String {
+        editor.process_event(Event::Key(KeyEvent::new(KeyCode::End, KeyModifiers::NONE)));
+        editor.process_event(Event::Key(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)));
+        String::from_utf8_lossy(&editor.document.contents()).into_owned()
+    }
+
+    fn mouse(kind: MouseEventKind, column: u16, row: u16) -> Event {
+        Event::Mouse(MouseEvent { kind, column, row, modifiers: KeyModifiers::NONE })
+    }
+
+    fn key(c: char) -> Event {
+        Event::Key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE))
+    }
+
+    #[test]
+    fn scrolling_after_a_click_does_not_select() {
+        let contents: String = (0..100).map(|n| ["line ", &n.to_string(), "\n"].concat()).collect();
+        let mut editor = editor(&contents);
+        editor.process_event(mouse(MouseEventKind::Down(MouseButton::Left), 2, 2));
+        editor.process_event(mouse(MouseEventKind::Up(MouseButton::Left), 2, 2));
+        for _ in 0..10 {
+            editor.process_event(mouse(MouseEventKind::ScrollDown, 0, 0));
+        }
+        let Position { x, y } = editor.cursor_position.clone();
+        assert!(y > 1);
+        editor.process_event(key('x'));
+        let mut expected: Vec<String> = contents.lines().map(String::from).collect();
+        if let Some(line) = expected.get_mut(y) {
+            line.insert(x, 'x');
+        }
+        assert_eq!(String::from_utf8_lossy(&editor.document.contents()), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn dragging_selects_from_the_click() {
+        let mut editor = editor("one two\n");
+        editor.process_event(mouse(MouseEventKind::Down(MouseButton::Left), 4, 1));
+        editor.process_event(mouse(MouseEventKind::Drag(MouseButton::Left), 7, 1));
+        editor.process_event(key('x'));
+        assert_eq!(String::from_utf8_lossy(&editor.document.contents()), "one x\n");
+    }
+
+    #[test]
+    fn enter_indents_like_the_rest_of_the_file() {
+        let mut editor = editor_for("tabs.c", "int main() {\n}\nvoid f() {\n\treturn;\n}\n");
+        assert_eq!(enter_at_end(&mut editor), "int main() {\n\t\n}\nvoid f() {\n\treturn;\n}\n");
+        let mut editor = editor_for("spaces.c", "int main() {\n}\n");
+        assert_eq!(enter_at_end(&mut editor), "int main() {\n    \n}\n");
+    }
+
+    #[test]
+    fn languages_can_be_set_to_indent_their_own_way() {
+        let mut editor = editor_for("two.c", "int main() {\n}\n");
+        editor.config.indents.insert("c".to_string(), Indent::Spaces(2));
+        assert_eq!(enter_at_end(&mut editor), "int main() {\n  \n}\n");
+        let mut editor = editor_for("off.c", "int main() {\n}\n");
+        editor.config.indents.insert("c".to_string(), Indent::Off);
+        assert_eq!(enter_at_end(&mut editor), "int main() {\n\n}\n");
+    }
+}
@@ -0,0 +1,238 @@
+use std::path::Path;
+
+pub struct FileType {
+    name: String,
+    hl_opts: HighlightingOptions,
+    indent_opts: IndentOptions,
+}
+
+/// How Enter indents the new line: it always keeps the current indentation, adds a
+/// level after one of `openers` and drops one when one of `closers` starts a line.
+#[derive(Default)]
+pub struct IndentOptions {
+    openers: &'static [char],
+    closers: &'static [char],
+    /// Spaces per level when indenting with spaces; 0 uses the tab width.
+    width: usize,
+}
+
+#[derive(Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct HighlightingOptions {
+    numbers: bool,
+    strings: &'static [char],
+    characters: bool,
+    comment: Option<&'static str>,
+    multiline_comment: Option<(&'static str, &'static str)>,
+    nested_comments: bool,
+    multiline_strings: bool,
+    raw_strings: bool,
+    triple_quotes: bool,
+    keywords: &'static [&'static str],
+    builtins: &'static [&'static str],
+    headings: bool,
+    code_spans: bool,
+}
+
+impl Default for FileType {
+    fn default() -> Self {
+        Self {
+            name: String::from("No filetype"),
+            hl_opts: HighlightingOptions::default(),
+            indent_opts: IndentOptions::default(),
+        }
+    }
+}
+
+impl FileType {
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+    pub fn highlighting_options(&self) -> &HighlightingOptions {
+        &self.hl_opts
+    }
+    pub fn indent_options(&self) -> &IndentOptions {
+        &self.indent_opts
+    }
+    pub fn from(file_name: &str) -> Self {
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let (name, hl_opts, indent_opts) = match extension.as_str() {
+            "rs" => ("Rust", HighlightingOptions {
+                numbers: true,
+                strings: &['"'],
+                characters: true,
+                comment: Some("//"),
+                multiline_comment: Some(("/*", "*/")),
+                nested_comments: true,
+                multiline_strings: true,
+                raw_strings: true,
+                keywords: RUST_KEYWORDS,
+                builtins: RUST_TYPES,
+                ..HighlightingOptions::default()
+            }, BRACES_INDENT),
+            "c" | "h" => ("C", HighlightingOptions {
+                numbers: true,
+                strings: &['"'],
+                characters: true,
+                comment: Some("//"),
+                multiline_comment: Some(("/*", "*/")),
+                keywords: C_KEYWORDS,
+                builtins: C_TYPES,
+                ..HighlightingOptions::default()
+            }, BRACES_INDENT),
+            "py" => ("Python", HighlightingOptions {
+                numbers: true,
+                strings: &['"', '\''],
+                comment: Some("#"),
+                triple_quotes: true,
+                keywords: PYTHON_KEYWORDS,
+                builtins: PYTHON_TYPES,
+                ..HighlightingOptions::default()
+            }, PYTHON_INDENT),
+            "json" => ("JSON", HighlightingOptions {
+                numbers: true,
+                strings: &['"'],
+                keywords: &["true", "false", "null"],
+                ..HighlightingOptions::default()
+            }, JSON_INDENT),
+            "toml" => ("TOML", HighlightingOptions {
+                numbers: true,
+                strings: &['"', '\''],
+                comment: Some("#"),
+                keywords: &["true", "false"],
+                headings: true,
+                ..HighlightingOptions::default()
+            }, BRACKETS_INDENT),
+            "md" | "markdown" => ("Markdown", HighlightingOptions {
+                headings: true,
+                code_spans: true,
+                ..HighlightingOptions::default()
+            }, IndentOptions::default()),
+            _ => return Self::default(),
+        };
+        Self {
+            name: String::from(name),
+            hl_opts,
+            indent_opts,
+        }
+    }
+}
+
+impl IndentOptions {
+    pub fn openers(&self) -> &[char] {
+        self.openers
+    }
+    pub fn closers(&self) -> &[char] {
+        self.closers
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+}
+
+impl HighlightingOptions {
+    pub fn numbers(&self) -> bool {
+        self.numbers
+    }
+    pub fn strings(&self) -> &[char] {
+        self.strings
+    }
+    pub fn characters(&self) -> bool {
+        self.characters
+    }
+    pub fn comment(&self) -> Option<&str> {
+        self.comment
+    }
+    pub fn multiline_comment(&self) -> Option<(&str, &str)> {
+        self.multiline_comment
+    }
+    pub fn nested_comments(&self) -> bool {
+        self.nested_comments
+    }
+    pub fn multiline_strings(&self) -> bool {
+        self.multiline_strings
+    }
+    pub fn raw_strings(&self) -> bool {
+        self.raw_strings
+    }
+    pub fn triple_quotes(&self) -> bool {
+        self.triple_quotes
+    }
+    pub fn keywords(&self) -> &[&str] {
+        self.keywords
+    }
+    pub fn builtins(&self) -> &[&str] {
+        self.builtins
+    }
+    /// Markdown `#` headings and TOML `[table]` headers.
+    pub fn headings(&self) -> bool {
+        self.headings
+    }
+    pub fn code_spans(&self) -> bool {
+        self.code_spans
+    }
+}
+
+const BRACES_INDENT: IndentOptions = IndentOptions {
+    openers: &['{', '(', '['],
+    closers: &['}', ')', ']'],
+    width: 4,
+};
+
+const PYTHON_INDENT: IndentOptions = IndentOptions {
+    openers: &[':', '{', '(', '['],
+    closers: &['}', ')', ']'],
+    width: 4,
+};
+
+const JSON_INDENT: IndentOptions = IndentOptions {
+    openers: &['{', '['],
+    closers: &['}', ']'],
+    width: 2,
+};
+
+const BRACKETS_INDENT: IndentOptions = IndentOptions {
+    openers: &['{', '['],
+    closers: &['}', ']'],
+    width: 4,
+};
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+    "mut", "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true",
+    "type", "unsafe", "use", "where", "while",
+];
+
+const RUST_TYPES: &[&str] = &[
+    "bool", "char", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64",
+    "u128", "usize", "f32", "f64", "str", "String", "Self", "Option", "Result", "Some", "None",
+    "Ok", "Err", "Vec", "Box",
+];
+
+const C_KEYWORDS: &[&str] = &[
+    "break", "case", "const", "continue", "default", "do", "else", "enum", "extern", "for",
+    "goto", "if", "inline", "register", "restrict", "return", "sizeof", "static", "struct",
+    "switch", "typedef", "union", "volatile", "while", "NULL", "true", "false",
+];
+
+const C_TYPES: &[&str] = &[
+    "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void", "bool",
+    "size_t", "ssize_t", "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t",
+    "uint32_t", "uint64_t", "FILE",
+];
+
+const PYTHON_KEYWORDS: &[&str] = &[
+    "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+    "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is",
+    "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with",
+    "yield", "True", "False", "None", "self",
+];
+
+const PYTHON_TYPES: &[&str] = &[
+    "bool", "bytes", "dict", "float", "int", "list", "object", "set", "str", "tuple",
+];
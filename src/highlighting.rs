@@ -0,0 +1,57 @@
+use crossterm::style::Color;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Type {
+    None,
+    Number,
+    String,
+    Character,
+    Comment,
+    Keyword,
+    Builtin,
+    Heading,
+    Match,
+    CurrentMatch,
+    /// Drawn in reverse video rather than with a color of its own.
+    Selection,
+}
+
+impl Type {
+    pub fn to_color(self) -> Color {
+        match self {
+            Type::Number => Color::Rgb { r: 220, g: 163, b: 163 },
+            Type::String => Color::Rgb { r: 211, g: 54, b: 130 },
+            Type::Character => Color::Rgb { r: 108, g: 113, b: 196 },
+            Type::Comment => Color::Rgb { r: 133, g: 153, b: 0 },
+            Type::Keyword => Color::Rgb { r: 181, g: 137, b: 0 },
+            Type::Builtin => Color::Rgb { r: 42, g: 161, b: 152 },
+            Type::Heading => Color::Rgb { r: 38, g: 139, b: 210 },
+            Type::Match => Color::DarkBlue,
+            Type::CurrentMatch => Color::DarkYellow,
+            Type::None | Type::Selection => Color::Reset,
+        }
+    }
+}
+
+/// A construct that is still open at the end of a row and continues on the next one.
+#[derive(PartialEq, Clone, Copy, Default)]
+pub enum State {
+    #[default]
+    Normal,
+    /// Inside `/* */`, nested this many levels deep.
+    BlockComment(usize),
+    String(char),
+    /// Inside `r#"..."#` with this many `#`.
+    RawString(usize),
+    TripleQuote(char),
+}
+
+impl State {
+    pub fn to_type(self) -> Type {
+        match self {
+            State::Normal => Type::None,
+            State::BlockComment(_) => Type::Comment,
+            State::String(_) | State::RawString(_) | State::TripleQuote(_) => Type::String,
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::Position;
+use unicode_segmentation::UnicodeSegmentation;
+
+// `at.x` counts bytes into the row, not graphemes: inserting a combining mark joins the
+// grapheme before it, so grapheme positions stop pointing at what was edited.
+#[derive(Clone)]
+pub enum Operation {
+    Insert { at: Position, text: String },
+    Delete { at: Position, text: String },
+}
+
+pub struct History {
+    undo_stack: Vec<Vec<Operation>>,
+    redo_stack: Vec<Vec<Operation>>,
+    // Where the next edit has to happen to be merged into the current group.
+    merge_at: Option<Position>,
+    saved_at: Option<usize>,
+    // Inside `begin_group`/`end_group`: `Some(false)` until the group's first operation.
+    group: Option<bool>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            merge_at: None,
+            saved_at: Some(0),
+            group: None,
+        }
+    }
+}
+
+impl History {
+    pub fn record(&mut self, operation: Operation) {
+        if !self.redo_stack.is_empty() {
+            if self.saved_at.is_some_and(|saved| saved > self.undo_stack.len()) {
+                self.saved_at = None;
+            }
+            self.redo_stack.clear();
+        }
+        if let Some(started) = self.group {
+            if started {
+                if let Some(group) = self.undo_stack.last_mut() {
+                    group.push(operation);
+                    return;
+                }
+            }
+            self.undo_stack.push(vec![operation]);
+            self.group = Some(true);
+            return;
+        }
+        let merge_at = Self::next_merge_position(&operation);
+        let mergeable = Self::is_keystroke(&operation) && match (&operation, &self.merge_at) {
+            (Operation::Insert { at, .. }, Some(expected)) => at == expected,
+            // Delete keeps the position, Backspace walks left over what it removed.
+            (Operation::Delete { at, text }, Some(expected)) => {
+                at.y == expected.y && (at.x == expected.x || at.x.saturating_add(text.len()) == expected.x)
+            }
+            _ => false,
+        };
+        match self.undo_stack.last_mut() {
+            Some(group) if mergeable && Self::same_kind(group, &operation) => group.push(operation),
+            _ => self.undo_stack.push(vec![operation]),
+        }
+        self.merge_at = merge_at;
+    }
+
+    pub fn begin_group(&mut self) {
+        self.group = Some(false);
+        self.merge_at = None;
+    }
+
+    pub fn end_group(&mut self) {
+        self.group = None;
+    }
+
+    pub fn undo(&mut self) -> Option<Vec<Operation>> {
+        let group = self.undo_stack.pop()?;
+        self.redo_stack.push(group.clone());
+        self.merge_at = None;
+        Some(group)
+    }
+
+    pub fn redo(&mut self) -> Option<Vec<Operation>> {
+        let group = self.redo_stack.pop()?;
+        self.undo_stack.push(group.clone());
+        self.merge_at = None;
+        Some(group)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved_at = Some(self.undo_stack.len());
+        self.merge_at = None;
+    }
+
+    // For changes that are not recorded as operations, so undo can no longer get
+    // back to the saved file.
+    pub fn forget_saved(&mut self) {
+        self.saved_at = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn is_at_saved(&self) -> bool {
+        self.saved_at == Some(self.undo_stack.len())
+    }
+
+    fn same_kind(group: &[Operation], operation: &Operation) -> bool {
+        matches!(
+            (group.last(), operation),
+            (Some(Operation::Insert { .. }), Operation::Insert { .. })
+                | (Some(Operation::Delete { .. }), Operation::Delete { .. })
+        )
+    }
+
+    // Whether the operation is a single grapheme, as typed or deleted by one key press.
+    // Pastes and other multi-grapheme edits are undone on their own.
+    fn is_keystroke(operation: &Operation) -> bool {
+        let (Operation::Insert { text, .. } | Operation::Delete { text, .. }) = operation;
+        text.graphemes(true).nth(1).is_none()
+    }
+
+    // Consecutive typing on one line forms a single undo step, and so does a run of
+    // Backspace or Delete presses. A newline or a multi-grapheme edit always closes the
+    // step.
+    fn next_merge_position(operation: &Operation) -> Option<Position> {
+        if !Self::is_keystroke(operation) {
+            return None;
+        }
+        match operation {
+            Operation::Insert { text, .. } | Operation::Delete { text, .. } if text.contains('\n') => None,
+            Operation::Insert { at, text } => Some(Position {
+                x: at.x.saturating_add(text.len()),
+                y: at.y,
+            }),
+            Operation::Delete { at, .. } => Some(at.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(x: usize, text: &str) -> Operation {
+        Operation::Insert {
+            at: Position { x, y: 0 },
+            text: text.to_string(),
+        }
+    }
+
+    fn delete(x: usize, text: &str) -> Operation {
+        Operation::Delete {
+            at: Position { x, y: 0 },
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn typing_merges_into_one_step() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.record(insert(1, "é"));
+        history.record(insert(3, "b"));
+        assert_eq!(history.undo().map(|group| group.len()), Some(3));
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn pastes_are_their_own_step() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.record(insert(1, "b"));
+        history.record(insert(2, "xyz"));
+        history.record(insert(5, "c"));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(2));
+    }
+
+    #[test]
+    fn deleted_words_are_their_own_step() {
+        let mut history = History::default();
+        history.record(delete(9, "a"));
+        history.record(delete(5, "word"));
+        history.record(delete(4, " "));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+    }
+
+    #[test]
+    fn typing_elsewhere_starts_a_new_step() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.record(insert(5, "b"));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+    }
+
+    #[test]
+    fn backspace_and_delete_runs_merge() {
+        let mut history = History::default();
+        history.record(delete(4, "é"));
+        history.record(delete(3, "c"));
+        history.record(delete(3, "d"));
+        assert_eq!(history.undo().map(|group| group.len()), Some(3));
+    }
+
+    #[test]
+    fn newline_closes_the_step() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.record(insert(1, "\n"));
+        history.record(insert(0, "b"));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(2));
+    }
+
+    #[test]
+    fn groups_collect_everything_in_between() {
+        let mut history = History::default();
+        history.begin_group();
+        history.record(delete(0, "abc"));
+        history.record(insert(0, "x"));
+        history.end_group();
+        history.record(insert(1, "y"));
+        assert_eq!(history.undo().map(|group| group.len()), Some(1));
+        assert_eq!(history.undo().map(|group| group.len()), Some(2));
+    }
+
+    #[test]
+    fn redo_is_dropped_by_a_new_edit() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.undo();
+        history.record(insert(0, "b"));
+        assert!(history.redo().is_none());
+    }
+
+    #[test]
+    fn saved_state_is_tracked_through_undo_and_redo() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.mark_saved();
+        history.record(insert(5, "b"));
+        assert!(!history.is_at_saved());
+        history.undo();
+        assert!(history.is_at_saved());
+        history.undo();
+        assert!(!history.is_at_saved());
+        history.redo();
+        assert!(history.is_at_saved());
+    }
+
+    #[test]
+    fn saved_state_is_lost_when_its_redo_is_dropped() {
+        let mut history = History::default();
+        history.record(insert(0, "a"));
+        history.mark_saved();
+        history.undo();
+        history.record(insert(0, "b"));
+        history.undo();
+        assert!(!history.is_at_saved());
+    }
+}
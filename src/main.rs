@@ -11,13 +11,29 @@ mod editor;
 mod terminal;
 mod document;
 mod row;
+mod rope;
+mod history;
+mod config;
+mod swap;
+mod search;
+mod highlighting;
+mod filetype;
+mod clipboard;
 
 use editor::Editor;
 pub use terminal::Terminal;
 pub use editor::Position;
 pub use editor::SearchDirection;
 pub use document::Document;
+pub use document::LineEnding;
+pub use document::DiskStatus;
 pub use row::Row;
+pub use config::Config;
+pub use config::LineNumbers;
+pub use config::Indent;
+pub use filetype::FileType;
+pub use filetype::HighlightingOptions;
+pub use filetype::IndentOptions;
 
 fn main() {
     Editor::default().run();
@@ -0,0 +1,629 @@
+use std::borrow::Cow;
+use std::cmp;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::row::{advance, grapheme_width};
+
+// A chunk is cut in two once it grows past `MAX_CHUNK` bytes and merged into a
+// neighbour once it shrinks below `MIN_CHUNK`. New chunks are cut at about half the
+// maximum, so it takes thousands of keystrokes in one place before either happens.
+const MAX_CHUNK: usize = 4096;
+const MIN_CHUNK: usize = MAX_CHUNK / 4;
+
+/// Text split into chunks of a few kilobytes, each starting and ending on a grapheme
+/// boundary, with Fenwick trees over the chunks' grapheme and byte counts. Looking up
+/// a grapheme or byte offset takes time logarithmic in the number of chunks, and an
+/// edit only rewrites the chunk it falls in, so typing into a line of several megabytes
+/// costs about as much as typing into a short one. Each chunk also knows how wide it is
+/// on screen, so finding a screen column only looks at the graphemes of one chunk.
+pub struct Rope {
+    // Never empty: an empty rope is a single empty chunk. Other chunks are never empty.
+    chunks: Vec<Chunk>,
+    graphemes: Fenwick,
+    bytes: Fenwick,
+}
+
+#[derive(Clone)]
+struct Chunk {
+    text: String,
+    // Byte offset where each grapheme starts, followed by `text.len()`, so grapheme `i`
+    // is `text[boundaries[i]..boundaries[i + 1]]`.
+    boundaries: Vec<usize>,
+    // The screen width of each run of graphemes between tabs, so one more run than
+    // there are tabs. Where a run starts depends on the tab stops, and so on where the
+    // chunk starts, but its width doesn't.
+    runs: Vec<usize>,
+}
+
+// Prefix sums over per-chunk counts that can be updated in place.
+struct Fenwick {
+    // One-based: `tree[i]` holds the sum of the values `i - lowbit(i)..i`.
+    tree: Vec<usize>,
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Self::from_chunks(Vec::new())
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        let mut boundaries: Vec<usize> = text.grapheme_indices(true).map(|(index, _)| index).collect();
+        boundaries.push(text.len());
+        Self::from_chunks(cut(text, &boundaries))
+    }
+}
+
+impl Rope {
+    /// Number of graphemes.
+    pub fn len(&self) -> usize {
+        self.graphemes.total()
+    }
+    pub fn len_bytes(&self) -> usize {
+        self.bytes.total()
+    }
+    pub fn grapheme(&self, at: usize) -> Option<&str> {
+        if at >= self.len() {
+            return None;
+        }
+        let (index, local) = self.locate(&self.graphemes, at);
+        self.chunks.get(index)?.grapheme(local)
+    }
+    pub fn graphemes(&self) -> impl Iterator<Item = &str> + '_ {
+        self.graphemes_from(0)
+    }
+    /// The graphemes from `at` on.
+    pub fn graphemes_from(&self, at: usize) -> impl Iterator<Item = &str> + '_ {
+        let (first, local) = self.locate(&self.graphemes, cmp::min(at, self.len()));
+        self.chunks.iter().enumerate().skip(first).flat_map(move |(index, chunk)| {
+            let from = if index == first { local } else { 0 };
+            (from..chunk.len()).filter_map(move |at| chunk.grapheme(at))
+        })
+    }
+    /// The screen column where grapheme `at` starts, with tabs `tab_width` apart.
+    pub fn column(&self, at: usize, tab_width: usize) -> usize {
+        let (index, local) = self.locate(&self.graphemes, cmp::min(at, self.len()));
+        let column = self
+            .chunks
+            .iter()
+            .take(index)
+            .fold(0, |column, chunk| chunk.end_column(column, tab_width));
+        self.chunks.get(index).map_or(column, |chunk| {
+            (0..local)
+                .filter_map(|at| chunk.grapheme(at))
+                .fold(column, |column, grapheme| column.saturating_add(advance(grapheme, column, tab_width)))
+        })
+    }
+    /// The grapheme drawn at screen column `column`, or the length past the end.
+    pub fn index_at_column(&self, column: usize, tab_width: usize) -> usize {
+        let mut start = 0;
+        let mut first: usize = 0;
+        for chunk in &self.chunks {
+            let end = chunk.end_column(start, tab_width);
+            if end > column {
+                let mut next = start;
+                for (at, grapheme) in (0..chunk.len()).filter_map(|at| chunk.grapheme(at)).enumerate() {
+                    next = next.saturating_add(advance(grapheme, next, tab_width));
+                    if next > column {
+                        return first.saturating_add(at);
+                    }
+                }
+            }
+            start = end;
+            first = first.saturating_add(chunk.len());
+        }
+        self.len()
+    }
+    /// The text in pieces, in order.
+    pub fn chunks(&self) -> impl Iterator<Item = &str> + '_ {
+        self.chunks.iter().map(|chunk| chunk.text.as_str())
+    }
+    /// Byte offset where grapheme `at` starts, or the length in bytes past the end.
+    pub fn byte_index(&self, at: usize) -> usize {
+        if at >= self.len() {
+            return self.len_bytes();
+        }
+        let (index, local) = self.locate(&self.graphemes, at);
+        self.bytes
+            .prefix(index)
+            .saturating_add(self.chunks.get(index).map_or(0, |chunk| chunk.byte_index(local)))
+    }
+    /// The grapheme starting exactly at `byte_index`, if any.
+    pub fn grapheme_index(&self, byte_index: usize) -> Option<usize> {
+        if byte_index > self.len_bytes() {
+            return None;
+        }
+        let (index, local) = self.locate(&self.bytes, byte_index);
+        let found = self.chunks.get(index)?.boundaries.binary_search(&local).ok()?;
+        Some(self.graphemes.prefix(index).saturating_add(found))
+    }
+    /// The grapheme starting at `byte_index`, or the one containing it.
+    pub fn nearest_grapheme(&self, byte_index: usize) -> usize {
+        let (index, local) = self.locate(&self.bytes, cmp::min(byte_index, self.len_bytes()));
+        let found = self.chunks.get(index).map_or(0, |chunk| {
+            chunk
+                .boundaries
+                .binary_search(&local)
+                .unwrap_or_else(|after| after.saturating_sub(1))
+        });
+        self.graphemes.prefix(index).saturating_add(found)
+    }
+    /// The bytes `start..end`, borrowed when they lie in one chunk.
+    pub fn slice(&self, start: usize, end: usize) -> Cow<'_, str> {
+        let end = cmp::min(end, self.len_bytes());
+        let start = cmp::min(start, end);
+        let (first, local) = self.locate(&self.bytes, start);
+        let length = end.saturating_sub(start);
+        if let Some(text) = self
+            .chunks
+            .get(first)
+            .and_then(|chunk| chunk.text.get(local..local.saturating_add(length)))
+        {
+            return Cow::Borrowed(text);
+        }
+        let mut text = String::with_capacity(length);
+        let mut offset = self.bytes.prefix(first);
+        for chunk in self.chunks.iter().skip(first) {
+            if offset >= end {
+                break;
+            }
+            let from = start.saturating_sub(offset);
+            let to = cmp::min(end.saturating_sub(offset), chunk.text.len());
+            text.push_str(chunk.text.get(from..to).unwrap_or_default());
+            offset = offset.saturating_add(chunk.text.len());
+        }
+        Cow::Owned(text)
+    }
+    /// The whole text, borrowed when it fits in one chunk.
+    pub fn text(&self) -> Cow<'_, str> {
+        self.slice(0, self.len_bytes())
+    }
+    /// Replaces the bytes `start..end` with `text`. The offsets don't have to fall on
+    /// grapheme boundaries. Only the graphemes next to the edit are segmented again,
+    /// since that is as far as a change can merge or split clusters.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    pub fn replace(&mut self, start: usize, end: usize, text: &str) {
+        let end = cmp::min(end, self.len_bytes());
+        let start = cmp::min(start, end);
+        let window_start = self.nearest_grapheme(start).saturating_sub(1);
+        let after_end = self
+            .grapheme_index(end)
+            .unwrap_or_else(|| self.nearest_grapheme(end).saturating_add(1));
+        let window_end = cmp::min(after_end.saturating_add(1), self.len());
+
+        let index = self.gather(window_start, window_end);
+        let before = self.counts(index);
+        let first_grapheme = self.graphemes.prefix(index);
+        let offset = self.bytes.prefix(index);
+        self.chunks[index].replace(
+            start - offset,
+            end - offset,
+            text,
+            window_start - first_grapheme,
+            window_end - first_grapheme,
+        );
+        self.rebalance(index, before);
+    }
+    /// Splits the rope at byte `at` and returns the part after it.
+    #[allow(clippy::indexing_slicing)]
+    pub fn split_off(&mut self, at: usize) -> Self {
+        let (index, local) = self.locate(&self.bytes, cmp::min(at, self.len_bytes()));
+        let mut tail = self.chunks.split_off(index.saturating_add(1));
+        let chunk = &mut self.chunks[index];
+        let rest = chunk.text.split_off(local);
+        *chunk = Chunk::new(std::mem::take(&mut chunk.text));
+        tail.insert(0, Chunk::new(rest));
+        *self = Self::from_chunks(std::mem::take(&mut self.chunks));
+        Self::from_chunks(tail)
+    }
+    pub fn append(&mut self, other: &Self) {
+        let seam = self.len_bytes();
+        let mut chunks = std::mem::take(&mut self.chunks);
+        chunks.extend(other.chunks.iter().cloned());
+        *self = Self::from_chunks(chunks);
+        // The graphemes on either side of the seam may join into one.
+        self.replace(seam, seam, "");
+    }
+
+    fn from_chunks(mut chunks: Vec<Chunk>) -> Self {
+        chunks.retain(|chunk| !chunk.text.is_empty());
+        if chunks.is_empty() {
+            chunks.push(Chunk::new(String::new()));
+        }
+        Self {
+            graphemes: Fenwick::new(chunks.iter().map(Chunk::len)),
+            bytes: Fenwick::new(chunks.iter().map(|chunk| chunk.text.len())),
+            chunks,
+        }
+    }
+    fn reindex(&mut self) {
+        *self = Self::from_chunks(std::mem::take(&mut self.chunks));
+    }
+    // The chunk holding offset `at` of the counts in `tree` and the offset within it; an
+    // offset on the border between two chunks belongs to the second one.
+    fn locate(&self, tree: &Fenwick, at: usize) -> (usize, usize) {
+        let (index, before) = tree.search(at);
+        if index < self.chunks.len() {
+            return (index, at.saturating_sub(before));
+        }
+        let last = self.chunks.len().saturating_sub(1);
+        (last, at.saturating_sub(tree.prefix(last)))
+    }
+    fn counts(&self, index: usize) -> (usize, usize) {
+        self.chunks
+            .get(index)
+            .map_or((0, 0), |chunk| (chunk.len(), chunk.text.len()))
+    }
+    // Moves graphemes `start..end` into one chunk and returns its index. Chunks entirely
+    // inside the range are merged whole; of the last one, only the graphemes inside the
+    // range are moved, which for a small edit is a grapheme or two.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn gather(&mut self, start: usize, end: usize) -> usize {
+        let (first, _) = self.locate(&self.graphemes, start);
+        if end <= start.saturating_add(1) {
+            return first;
+        }
+        let (last, last_local) = self.locate(&self.graphemes, end - 1);
+        if last <= first {
+            return first;
+        }
+        let before = (self.counts(first), self.counts(last));
+        let moved = self.chunks[last].take_front(last_local + 1);
+        let middle: Vec<Chunk> = self.chunks.drain(first + 1..last).collect();
+        for chunk in &middle {
+            self.chunks[first].push(&chunk.text, &chunk.boundaries);
+        }
+        self.chunks[first].push(&moved.text, &moved.boundaries);
+        if middle.is_empty() && !self.chunks[first + 1].text.is_empty() {
+            self.update(first, before.0);
+            self.update(first + 1, before.1);
+        } else {
+            self.reindex();
+        }
+        first
+    }
+    // Cuts the chunk at `index` after an edit if it grew too large, merges it into a
+    // neighbour if it got too small, and otherwise just updates its counts.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn rebalance(&mut self, index: usize, before: (usize, usize)) {
+        let size = self.chunks[index].text.len();
+        if size > MAX_CHUNK {
+            let chunk = &self.chunks[index];
+            let pieces = cut(&chunk.text, &chunk.boundaries);
+            self.chunks.splice(index..=index, pieces);
+            self.reindex();
+        } else if size < MIN_CHUNK && self.chunks.len() > 1 {
+            let left = if index + 1 < self.chunks.len() { index } else { index - 1 };
+            let right = self.chunks.remove(left + 1);
+            self.chunks[left].push(&right.text, &right.boundaries);
+            if self.chunks[left].text.len() > MAX_CHUNK {
+                let chunk = &self.chunks[left];
+                let pieces = cut(&chunk.text, &chunk.boundaries);
+                self.chunks.splice(left..=left, pieces);
+            }
+            self.reindex();
+        } else {
+            self.update(index, before);
+        }
+    }
+    fn update(&mut self, index: usize, before: (usize, usize)) {
+        let (graphemes, bytes) = self.counts(index);
+        self.graphemes.set(index, before.0, graphemes);
+        self.bytes.set(index, before.1, bytes);
+    }
+}
+
+impl Chunk {
+    fn new(text: String) -> Self {
+        let mut boundaries: Vec<usize> = text.grapheme_indices(true).map(|(index, _)| index).collect();
+        boundaries.push(text.len());
+        Self::with_boundaries(text, boundaries)
+    }
+    fn with_boundaries(text: String, boundaries: Vec<usize>) -> Self {
+        let mut chunk = Self { text, boundaries, runs: Vec::new() };
+        chunk.measure();
+        chunk
+    }
+    fn measure(&mut self) {
+        let mut runs: Vec<usize> = vec![0];
+        for at in 0..self.len() {
+            match self.grapheme(at) {
+                Some("\t") => runs.push(0),
+                Some(grapheme) => {
+                    if let Some(run) = runs.last_mut() {
+                        *run = run.saturating_add(grapheme_width(grapheme));
+                    }
+                }
+                None => (),
+            }
+        }
+        self.runs = runs;
+    }
+    // The screen column after the chunk when it starts at `column`.
+    fn end_column(&self, column: usize, tab_width: usize) -> usize {
+        let mut runs = self.runs.iter();
+        let first = runs.next().map_or(column, |run| column.saturating_add(*run));
+        runs.fold(first, |column, run| {
+            column.saturating_add(advance("\t", column, tab_width)).saturating_add(*run)
+        })
+    }
+    fn len(&self) -> usize {
+        self.boundaries.len().saturating_sub(1)
+    }
+    fn byte_index(&self, at: usize) -> usize {
+        self.boundaries.get(at).copied().unwrap_or(self.text.len())
+    }
+    fn grapheme(&self, at: usize) -> Option<&str> {
+        self.text.get(*self.boundaries.get(at)?..*self.boundaries.get(at.saturating_add(1))?)
+    }
+    // Appends `text`, whose graphemes start at `boundaries` (followed by its length).
+    fn push(&mut self, text: &str, boundaries: &[usize]) {
+        let offset = self.text.len();
+        self.boundaries.pop();
+        self.boundaries
+            .extend(boundaries.iter().map(|boundary| boundary.saturating_add(offset)));
+        self.text.push_str(text);
+        self.measure();
+    }
+    // Removes the first `count` graphemes and returns them as a chunk of their own.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn take_front(&mut self, count: usize) -> Self {
+        let count = cmp::min(count, self.len());
+        let end = self.boundaries[count];
+        let text: String = self.text.drain(..end).collect();
+        let mut boundaries: Vec<usize> = self.boundaries.drain(..count).collect();
+        boundaries.push(end);
+        for boundary in &mut self.boundaries {
+            *boundary -= end;
+        }
+        self.measure();
+        Self::with_boundaries(text, boundaries)
+    }
+    // Replaces the bytes `start..end` with `text` and segments graphemes
+    // `window_start..window_end`, which cover the edit and one grapheme either side of
+    // it, again. The boundaries after the window are shifted, never recomputed.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn replace(&mut self, start: usize, end: usize, text: &str, window_start: usize, window_end: usize) {
+        self.text.replace_range(start..end, text);
+        let inserted = text.len();
+        let removed = end - start;
+        let window_start_byte = self.boundaries[window_start];
+        for boundary in &mut self.boundaries[window_end..] {
+            *boundary = *boundary + inserted - removed;
+        }
+        let window_end_byte = self.boundaries[window_end];
+        let window: Vec<usize> = self.text[window_start_byte..window_end_byte]
+            .grapheme_indices(true)
+            .map(|(index, _)| window_start_byte + index)
+            .collect();
+        self.boundaries.splice(window_start..window_end, window);
+        self.measure();
+    }
+}
+
+impl Fenwick {
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn new(values: impl Iterator<Item = usize>) -> Self {
+        let mut tree: Vec<usize> = std::iter::once(0).chain(values).collect();
+        for index in 1..tree.len() {
+            let parent = index + lowbit(index);
+            if parent < tree.len() {
+                tree[parent] += tree[index];
+            }
+        }
+        Self { tree }
+    }
+    fn len(&self) -> usize {
+        self.tree.len().saturating_sub(1)
+    }
+    fn total(&self) -> usize {
+        self.prefix(self.len())
+    }
+    // Sum of the first `count` values.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn prefix(&self, count: usize) -> usize {
+        let mut index = cmp::min(count, self.len());
+        let mut sum = 0;
+        while index > 0 {
+            sum += self.tree[index];
+            index -= lowbit(index);
+        }
+        sum
+    }
+    // Changes value `index` from `old` to `new`.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn set(&mut self, index: usize, old: usize, new: usize) {
+        let mut position = index + 1;
+        while position < self.tree.len() {
+            self.tree[position] = self.tree[position] + new - old;
+            position += lowbit(position);
+        }
+    }
+    // How many leading values add up to at most `target`, and their sum.
+    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+    fn search(&self, target: usize) -> (usize, usize) {
+        let mut position = 0;
+        let mut sum = 0;
+        let mut step = self.len().checked_next_power_of_two().unwrap_or(0);
+        while step > 0 {
+            let next = position + step;
+            if next < self.tree.len() && sum + self.tree[next] <= target {
+                position = next;
+                sum += self.tree[next];
+            }
+            step /= 2;
+        }
+        (position, sum)
+    }
+}
+
+#[allow(clippy::integer_arithmetic)]
+fn lowbit(index: usize) -> usize {
+    index & index.wrapping_neg()
+}
+
+// Cuts `text`, whose graphemes start at `boundaries` (followed by `text.len()`), into
+// chunks of about half the maximum size.
+#[allow(clippy::integer_arithmetic, clippy::indexing_slicing)]
+fn cut(text: &str, boundaries: &[usize]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut first = 0;
+    for index in 1..boundaries.len() {
+        let is_last = index + 1 == boundaries.len();
+        if is_last || boundaries[index] - boundaries[first] >= MAX_CHUNK / 2 {
+            let start = boundaries[first];
+            chunks.push(Chunk::with_boundaries(
+                text[start..boundaries[index]].to_string(),
+                boundaries[first..=index].iter().map(|boundary| boundary - start).collect(),
+            ));
+            first = index;
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fixed pseudo-random sequence, so failures reproduce.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, bound: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            usize::try_from(self.0 >> 33).unwrap_or_default() % bound.max(1)
+        }
+    }
+
+    const PIECES: [&str; 8] = ["a", "bc ", "é", "e\u{301}", "\u{301}", "日本", "👍🏽", "\t"];
+
+    fn check(rope: &Rope, expected: &str) {
+        assert_eq!(rope.text(), expected);
+        let graphemes: Vec<&str> = expected.graphemes(true).collect();
+        assert_eq!(rope.graphemes().collect::<Vec<_>>(), graphemes);
+        assert_eq!(rope.len(), graphemes.len());
+        assert_eq!(rope.len_bytes(), expected.len());
+        let mut byte = 0;
+        for (index, grapheme) in graphemes.iter().enumerate() {
+            assert_eq!(rope.byte_index(index), byte);
+            assert_eq!(rope.grapheme_index(byte), Some(index));
+            assert_eq!(rope.grapheme(index), Some(*grapheme));
+            byte += grapheme.len();
+        }
+        assert_eq!(rope.byte_index(graphemes.len()), expected.len());
+        for chunk in &rope.chunks {
+            assert!(!chunk.text.is_empty() || rope.chunks.len() == 1);
+            assert!(chunk.text.len() <= MAX_CHUNK);
+        }
+    }
+
+    fn random_text(random: &mut Lcg, pieces: usize) -> String {
+        (0..pieces).map(|_| PIECES[random.below(PIECES.len())]).collect()
+    }
+
+    // A random position that is a character boundary but not always a grapheme one.
+    fn random_offset(random: &mut Lcg, text: &str) -> usize {
+        let offsets: Vec<usize> = text.char_indices().map(|(index, _)| index).chain([text.len()]).collect();
+        offsets[random.below(offsets.len())]
+    }
+
+    #[test]
+    fn edits_match_a_plain_string() {
+        let mut random = Lcg(7);
+        let mut expected = random_text(&mut random, 6000);
+        let mut rope = Rope::from(expected.as_str());
+        check(&rope, &expected);
+        for step in 0..600 {
+            let start = random_offset(&mut random, &expected);
+            let end = if step % 3 == 0 {
+                start
+            } else {
+                let rest: String = expected[start..].chars().take(12).collect();
+                start + random_offset(&mut random, &rest)
+            };
+            let length = if step % 50 == 0 { 3000 } else { random.below(4) };
+            let text = random_text(&mut random, length);
+            expected.replace_range(start..end, &text);
+            rope.replace(start, end, &text);
+            assert_eq!(rope.text(), expected);
+        }
+        check(&rope, &expected);
+    }
+
+    #[test]
+    fn edits_at_chunk_borders_resegment_across_them() {
+        let text = "x".repeat(MAX_CHUNK * 3);
+        let mut rope = Rope::from(text.as_str());
+        let border = rope.chunks[0].text.len();
+        rope.replace(border, border, "\u{301}");
+        let mut expected = text.clone();
+        expected.insert(border, '\u{301}');
+        check(&rope, &expected);
+        assert_eq!(rope.grapheme(border - 1), Some("x\u{301}"));
+
+        let border = rope.bytes.prefix(2);
+        rope.replace(border - 1, border + 1, "");
+        expected.replace_range(border - 1..=border, "");
+        check(&rope, &expected);
+    }
+
+    #[test]
+    fn deleting_everything_leaves_an_empty_rope() {
+        let text = "ab\u{301}".repeat(MAX_CHUNK);
+        let mut rope = Rope::from(text.as_str());
+        rope.replace(0, text.len(), "");
+        check(&rope, "");
+        rope.replace(0, 0, "new");
+        check(&rope, "new");
+    }
+
+    #[test]
+    fn split_off_and_append_round_trip() {
+        let mut random = Lcg(11);
+        let text = random_text(&mut random, 5000);
+        for _ in 0..20 {
+            let at = random_offset(&mut random, &text);
+            let mut head = Rope::from(text.as_str());
+            let tail = head.split_off(at);
+            check(&head, &text[..at]);
+            check(&tail, &text[at..]);
+            head.append(&tail);
+            check(&head, &text);
+        }
+    }
+
+    #[test]
+    fn columns_match_a_scan() {
+        let mut random = Lcg(5);
+        let text = random_text(&mut random, 2500);
+        let mut rope = Rope::from(text.as_str());
+        rope.replace(100, 100, "\t日");
+        rope.replace(MAX_CHUNK, MAX_CHUNK + 3, "");
+        let text = rope.text().into_owned();
+        for tab_width in [1, 4, 8] {
+            let mut column = 0;
+            let mut expected_index = 0;
+            for (index, grapheme) in text.graphemes(true).enumerate() {
+                assert_eq!(rope.column(index, tab_width), column);
+                let next = column + advance(grapheme, column, tab_width);
+                for inside in column..next {
+                    assert_eq!(rope.index_at_column(inside, tab_width), index);
+                }
+                column = next;
+                expected_index = index + 1;
+            }
+            assert_eq!(rope.column(expected_index, tab_width), column);
+            assert_eq!(rope.index_at_column(column, tab_width), rope.len());
+        }
+    }
+
+    #[test]
+    fn slices_span_chunks() {
+        let text: String = "abcdefghijklmnopqrstuvwxyz".chars().cycle().take(MAX_CHUNK * 2).collect();
+        let rope = Rope::from(text.as_str());
+        assert!(matches!(rope.slice(1, 10), Cow::Borrowed("bcdefghij")));
+        assert_eq!(rope.slice(100, text.len() - 100), &text[100..text.len() - 100]);
+        assert_eq!(rope.slice(text.len(), text.len() + 5), "");
+    }
+}
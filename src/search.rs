@@ -0,0 +1,162 @@
+use regex::{Regex, RegexBuilder};
+use crate::Position;
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum CaseMode {
+    #[default]
+    Sensitive,
+    Insensitive,
+    /// Case-sensitive only when the query contains an uppercase letter.
+    Smart,
+}
+
+impl CaseMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Sensitive => Self::Insensitive,
+            Self::Insensitive => Self::Smart,
+            Self::Smart => Self::Sensitive,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
+pub struct SearchOptions {
+    pub regex: bool,
+    pub case: CaseMode,
+    pub whole_word: bool,
+}
+
+enum Matcher {
+    Plain(String),
+    // `expand` is false when a plain query was turned into a regex for case folding or
+    // word boundaries, so `$1` in its replacement stays literal.
+    Regex { regex: Regex, expand: bool },
+}
+
+/// A compiled search query: either a plain substring or a regular expression.
+pub struct Query {
+    matcher: Matcher,
+}
+
+impl Query {
+    /// Fails with a one-line description when `text` is not a valid regular expression.
+    pub fn new(text: &str, options: SearchOptions) -> Result<Self, String> {
+        let ignore_case = match options.case {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !has_uppercase(text, options.regex),
+        };
+        if !options.regex && !ignore_case && !options.whole_word {
+            return Ok(Self {
+                matcher: Matcher::Plain(text.to_string()),
+            });
+        }
+        let mut pattern = if options.regex {
+            text.to_string()
+        } else {
+            regex::escape(text)
+        };
+        if options.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|error| describe(&error))?;
+        Ok(Self {
+            matcher: Matcher::Regex {
+                regex,
+                expand: options.regex,
+            },
+        })
+    }
+
+    /// Byte range of the first match in `haystack` that starts at or after `start`.
+    /// Regular expressions see the whole haystack, so anchors and `\b` keep working.
+    pub fn find_at(&self, haystack: &str, start: usize) -> Option<(usize, usize)> {
+        match &self.matcher {
+            Matcher::Plain(query) => haystack
+                .get(start..)?
+                .find(query.as_str())
+                .map(|index| (start.saturating_add(index), start.saturating_add(index).saturating_add(query.len()))),
+            Matcher::Regex { regex, .. } => regex
+                .find_at(haystack, start)
+                .map(|found| (found.start(), found.end())),
+        }
+    }
+
+    /// The replacement for the match starting at byte `start` of `haystack`.
+    pub fn expand(&self, haystack: &str, start: usize, replacement: &str) -> String {
+        match &self.matcher {
+            Matcher::Plain(_) | Matcher::Regex { expand: false, .. } => replacement.to_string(),
+            Matcher::Regex { regex, .. } => {
+                let mut result = String::new();
+                if let Some(captures) = regex.captures_at(haystack, start) {
+                    captures.expand(replacement, &mut result);
+                }
+                result
+            }
+        }
+    }
+}
+
+/// Every match of a query in the document, in document order, so that counting and
+/// highlighting don't need to search again while the user moves between matches.
+pub struct Matches {
+    query: String,
+    options: SearchOptions,
+    ranges: Vec<(Position, Position)>,
+}
+
+impl Matches {
+    pub fn new(query: &str, options: SearchOptions, ranges: Vec<(Position, Position)>) -> Self {
+        Self {
+            query: query.to_string(),
+            options,
+            ranges,
+        }
+    }
+    pub fn is_for(&self, query: &str, options: SearchOptions) -> bool {
+        self.query == query && self.options == options
+    }
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+    /// Index of the first match that starts at or after `position`.
+    pub fn index_from(&self, position: &Position) -> usize {
+        self.ranges.partition_point(|(start, _)| (start.y, start.x) < (position.y, position.x))
+    }
+    /// The matches that start in row `y`.
+    pub fn in_row(&self, y: usize) -> &[(Position, Position)] {
+        let first = self.ranges.partition_point(|(start, _)| start.y < y);
+        let last = self.ranges.partition_point(|(start, _)| start.y <= y);
+        self.ranges.get(first..last).unwrap_or_default()
+    }
+}
+
+// Uppercase letters in a regex escape (`\W`, `\S`, ...) don't make a query case-sensitive.
+fn has_uppercase(text: &str, regex: bool) -> bool {
+    let mut escaped = false;
+    for c in text.chars() {
+        if c.is_uppercase() && !escaped {
+            return true;
+        }
+        escaped = regex && c == '\\' && !escaped;
+    }
+    false
+}
+
+// regex reports syntax errors over several lines with the pattern drawn above a caret;
+// the message bar only has room for the explanation on the last line.
+fn describe(error: &regex::Error) -> String {
+    let message = error.to_string();
+    message
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or(&message)
+        .trim()
+        .trim_start_matches("error: ")
+        .to_string()
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+
+/// Where the swap file for `file_name` lives: `.name.hecto.swp` next to the file, or,
+/// when a swap directory is configured, the file's full path with `/` replaced by `%`.
+/// The `hecto` part keeps it apart from Vim's `.name.swp`, which is in another format.
+pub fn path(file_name: &str, swap_dir: Option<&Path>) -> PathBuf {
+    let path = Path::new(file_name);
+    if let Some(swap_dir) = swap_dir {
+        let absolute = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let name = absolute.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "%");
+        return swap_dir.join(format!("{}.hecto.swp", name));
+    }
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".hecto.swp");
+    path.with_file_name(name)
+}
+
+/// Writes `contents` to the swap file, readable and writable only by its owner: it holds
+/// unsaved text that may come from a file nobody else can read.
+pub fn write(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to a new file; one written by an older version may be
+    // readable by everyone.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+/// A line diff between the file on disk and the swap file: the unchanged lines around
+/// the change are skipped and everything in between is shown as removed and added.
+pub fn diff(on_disk: &str, swapped: &str) -> String {
+    let old: Vec<&str> = on_disk.lines().collect();
+    let new: Vec<&str> = swapped.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if prefix == old.len() && prefix == new.len() {
+        return String::from("Swap file matches the file on disk.");
+    }
+    let mut result = format!(
+        "@@ line {}: -{} +{} @@\n",
+        prefix.saturating_add(1),
+        old.len().saturating_sub(prefix).saturating_sub(suffix),
+        new.len().saturating_sub(prefix).saturating_sub(suffix),
+    );
+    let removed = old[prefix..old.len().saturating_sub(suffix)].iter().map(|line| ('-', line));
+    let added = new[prefix..new.len().saturating_sub(suffix)].iter().map(|line| ('+', line));
+    for (marker, line) in removed.chain(added) {
+        result.push(marker);
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_files_are_not_named_like_vims() {
+        assert_eq!(path("dir/notes.txt", None), Path::new("dir/.notes.txt.hecto.swp"));
+        assert_eq!(path("/a/b.rs", Some(Path::new("/swap"))), Path::new("/swap/%a%b.rs.hecto.swp"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn swap_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("hecto-swap-{}", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write(&path, b"unsaved").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(fs::read(&path).unwrap(), b"unsaved");
+        let _ = fs::remove_file(&path);
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
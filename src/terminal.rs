@@ -2,34 +2,62 @@ use std::io::{self, Write};
 use crossterm::{
     execute,
     terminal::{size,Clear,ClearType},
-    event::{read, Event, KeyEvent},
+    event::{
+        poll, read, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste,
+        EnableMouseCapture, Event,
+    },
     cursor::{self,MoveTo},
     style::{self, Color},
 };
 use crate::Position;
+use std::time::Duration;
+use base64::Engine;
 
 pub struct Size {
     pub width: u16,
     pub height: u16,
 }
 
+impl Size {
+    // The rows left for text once the bars at the top and bottom are taken off.
+    fn from(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height: height.saturating_sub(3),
+        }
+    }
+}
+
 pub struct Terminal {
     size: Size,
     _stdout: std::io::Stdout,
 }
 
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), DisableMouseCapture, DisableBracketedPaste);
+    }
+}
+
 impl Terminal {
     pub fn default() -> Result<Self,std::io::Error> {
-        let size = size()?;
+        let (width, height) = size()?;
+        execute!(io::stdout(), EnableMouseCapture, EnableBracketedPaste)?;
         Ok(Self {
-            size: Size {
-                width: size.0,
-                height: size.1.saturating_sub(3),
-            },
+            size: Size::from(width, height),
             _stdout: io::stdout(),
         })
     }
 
+    /// A terminal of the given size that leaves the real one alone, for tests.
+    #[cfg(test)]
+    pub fn with_size(width: u16, height: u16) -> Self {
+        Self {
+            size: Size::from(width, height),
+            _stdout: io::stdout(),
+        }
+    }
+
     pub fn size(&self) ->&Size {
         &self.size
     }
@@ -50,12 +78,33 @@ impl Terminal {
         io::stdout().flush()
     }
 
-    pub fn read_key_event() -> Result<KeyEvent, std::io::Error> {
-        loop {
-            if let Event::Key(key_event) = read().unwrap() {
-                return Ok(key_event);
-            }
+    /// Reads the next input event. A resize is returned too, after `size` has been
+    /// updated, so the caller can redraw right away. Pasted text arrives as a single
+    /// event with its line endings turned into `\n`.
+    pub fn read_event(&mut self) -> Result<Event, std::io::Error> {
+        let event = match read()? {
+            Event::Paste(text) => Event::Paste(text.replace("\r\n", "\n").replace('\r', "\n")),
+            event => event,
+        };
+        if let Event::Resize(width, height) = event {
+            self.size = Size::from(width, height);
         }
+        Ok(event)
+    }
+
+    /// Puts `text` on the clipboard of the terminal emulator with an OSC 52 sequence,
+    /// which also reaches the local clipboard over SSH. Terminals that don't support
+    /// it ignore the sequence.
+    pub fn copy_to_clipboard(text: &str) -> Result<(), std::io::Error> {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b]52;c;{}\x07", encoded)?;
+        stdout.flush()
+    }
+
+    /// Waits up to `timeout` for input, so the caller can do background work in between.
+    pub fn poll(timeout: Duration) -> Result<bool, std::io::Error> {
+        poll(timeout)
     }
 
     pub fn cursor_hide() {